@@ -0,0 +1,115 @@
+//! Prepares `schema.graphql` for juniper-from-schema.
+//!
+//! juniper-from-schema rejects directives it doesn't know, so the `@auth` directives are removed
+//! from the schema it's given. Each `@auth(requires: ROLE)` becomes a `FieldRule` constant in
+//! `auth_rules.rs`, which `src/graphql/authorization.rs` includes.
+
+use graphql_parser::{
+    query::{Type, Value},
+    schema::{Definition, Document, TypeDefinition},
+};
+use std::{env, fmt::Write, fs, path::Path};
+
+const SCHEMA: &str = "schema.graphql";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SCHEMA);
+
+    let source = fs::read_to_string(SCHEMA).expect("read schema.graphql");
+    let mut document = graphql_parser::parse_schema(&source)
+        .unwrap_or_else(|err| panic!("Invalid `{}`: {}", SCHEMA, err));
+    let rules = take_auth_rules(&mut document);
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is set by Cargo");
+    let out_dir = Path::new(&out_dir);
+    let schema_path = out_dir.join(SCHEMA);
+    fs::write(&schema_path, document.to_string()).expect("write schema");
+    fs::write(
+        out_dir.join("schema.rs"),
+        format!(
+            "graphql_schema_from_file!({:?}, error_type: Error);\n",
+            schema_path
+        ),
+    )
+    .expect("write schema.rs");
+    fs::write(out_dir.join("auth_rules.rs"), rules).expect("write auth_rules.rs");
+}
+
+/// Remove `@auth` and its definition from `document` and return the generated rules.
+fn take_auth_rules(document: &mut Document) -> String {
+    document.definitions.retain(|definition| match definition {
+        Definition::DirectiveDefinition(directive) => directive.name != "auth",
+        _ => true,
+    });
+
+    let mut rules = String::new();
+    for definition in &mut document.definitions {
+        let object = match definition {
+            Definition::TypeDefinition(TypeDefinition::Object(object)) => object,
+            _ => continue,
+        };
+
+        for field in &mut object.fields {
+            let index = match field.directives.iter().position(|dir| dir.name == "auth") {
+                Some(index) => index,
+                None => continue,
+            };
+            let directive = field.directives.remove(index);
+            let field_name = format!("{}.{}", object.name, field.name);
+
+            let role = match directive.arguments.as_slice() {
+                [(argument, Value::Enum(role))] if argument == "requires" => role,
+                _ => panic!(
+                    "`@auth` on `{}` must be `@auth(requires: ROLE)`",
+                    field_name
+                ),
+            };
+            // Denied fields resolve to `null`, which would null the parent instead if the field
+            // was non-null.
+            if let Type::NonNullType(_) = field.field_type {
+                panic!("`{}` uses `@auth` so it must be nullable", field_name);
+            }
+
+            writeln!(
+                rules,
+                "pub const {}: FieldRule = FieldRule {{ field: {:?}, requires: Role::{} }};",
+                screaming_snake_case(&format!("{}_{}", object.name, field.name)),
+                field_name,
+                upper_camel_case(role),
+            )
+            .unwrap();
+        }
+    }
+    rules
+}
+
+/// `createUser` becomes `CREATE_USER`.
+fn screaming_snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (index, c) in name.chars().enumerate() {
+        if c.is_uppercase() && index > 0 && !out.ends_with('_') {
+            out.push('_');
+        }
+        out.push(c.to_ascii_uppercase());
+    }
+    out
+}
+
+/// The name juniper-from-schema gives enum variants, so `ADMIN` becomes `Admin`.
+fn upper_camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| {
+                    first
+                        .to_uppercase()
+                        .chain(chars.flat_map(char::to_lowercase))
+                })
+                .into_iter()
+                .flatten()
+                .collect::<String>()
+        })
+        .collect()
+}
@@ -0,0 +1,3 @@
+DROP INDEX users_name_tsv_idx;
+
+ALTER TABLE users DROP COLUMN name_tsv;
@@ -0,0 +1,9 @@
+-- `name_tsv` is only used through raw SQL in the search resolver, so it isn't part of
+-- `src/schema.rs`. Diesel has no built in type for `tsvector`.
+--
+-- We use the `simple` configuration since names shouldn't be stemmed.
+ALTER TABLE users
+  ADD COLUMN name_tsv tsvector
+  GENERATED ALWAYS AS (to_tsvector('simple', name)) STORED;
+
+CREATE INDEX users_name_tsv_idx ON users USING GIN (name_tsv);
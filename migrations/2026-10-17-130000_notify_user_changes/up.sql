@@ -0,0 +1,17 @@
+-- Publish every change to `users` on the `user_changes` channel so GraphQL subscriptions also see
+-- changes made by other services. The payload is `{"op": "INSERT" | "UPDATE" | "DELETE", "id": 1}`.
+--
+-- Notifications are only delivered when the transaction commits.
+CREATE FUNCTION notify_user_change() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify(
+    'user_changes',
+    json_build_object('op', TG_OP, 'id', COALESCE(NEW.id, OLD.id))::text
+  );
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_notify_change
+  AFTER INSERT OR UPDATE OR DELETE ON users
+  FOR EACH ROW EXECUTE FUNCTION notify_user_change();
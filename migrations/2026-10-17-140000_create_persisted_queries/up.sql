@@ -0,0 +1,6 @@
+-- Queries registered with automatic persisted queries, when `persisted_queries.store` is
+-- `postgres`.
+CREATE TABLE persisted_queries (
+    sha256_hash text PRIMARY KEY,
+    query text NOT NULL
+);
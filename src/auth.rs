@@ -0,0 +1,198 @@
+//! Authentication with JWT bearer tokens.
+//!
+//! Tokens are signed with HS256 or RS256 using keys from the `auth` section of `Rocket.toml`.
+//! Requests with an invalid token are rejected with `401 Unauthorized` before any GraphQL is
+//! executed. Requests without a token are only allowed if `auth.allow_anonymous` is set.
+//!
+//! The `role` claim is either `"user"`, the default, or `"admin"`. Fields that require a role are
+//! authorized by the resolvers, see `graphql::authorization`.
+
+use crate::{error::Error, graphql::Role};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rocket::{
+    figment::Figment,
+    http::{ContentType, Header, Status},
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+    response::{self, Responder},
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{fmt, sync::Arc};
+
+#[derive(Debug, Deserialize)]
+struct AuthConfig {
+    #[serde(default)]
+    allow_anonymous: bool,
+    hs256_secret: Option<String>,
+    /// A PEM encoded RSA public key.
+    rs256_public_key: Option<String>,
+}
+
+/// Verifies bearer tokens. Managed by Rocket in an `Arc`, so WebSocket sessions can share it.
+pub struct Authenticator {
+    allow_anonymous: bool,
+    hs256_key: Option<DecodingKey>,
+    rs256_key: Option<DecodingKey>,
+}
+
+/// The caller of a request, as identified by a valid token.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    /// The `sub` claim of the token.
+    #[allow(dead_code)] // No resolvers depend on the subject yet.
+    pub subject: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    role: Option<String>,
+}
+
+/// A request guard that authenticates the caller. `None` means the request is anonymous.
+pub struct Authentication(pub Option<CurrentUser>);
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    MalformedHeader,
+    InvalidToken(jsonwebtoken::errors::Error),
+    UnsupportedAlgorithm(Algorithm),
+    UnknownRole(String),
+}
+
+impl Authenticator {
+    pub fn from_figment(figment: &Figment) -> Self {
+        let config = figment
+            .extract_inner::<AuthConfig>("auth")
+            .unwrap_or_else(|err| panic!("Invalid auth config: {}", err));
+
+        let hs256_key = config
+            .hs256_secret
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+        let rs256_key = config.rs256_public_key.map(|pem| {
+            DecodingKey::from_rsa_pem(pem.as_bytes())
+                .unwrap_or_else(|err| panic!("Invalid `auth.rs256_public_key`: {}", err))
+        });
+
+        if !config.allow_anonymous && hs256_key.is_none() && rs256_key.is_none() {
+            panic!("`auth.allow_anonymous` is disabled but no keys are configured");
+        }
+
+        Authenticator {
+            allow_anonymous: config.allow_anonymous,
+            hs256_key,
+            rs256_key,
+        }
+    }
+
+    /// Authenticate a request from its `Authorization` header, or the same value sent some other
+    /// way by clients that can't set headers.
+    pub fn authenticate(&self, header: Option<&str>) -> Result<Option<CurrentUser>, AuthError> {
+        let header = match header {
+            Some(header) => header,
+            None if self.allow_anonymous => return Ok(None),
+            None => return Err(AuthError::MissingToken),
+        };
+
+        let token = header
+            .strip_prefix("Bearer ")
+            .ok_or(AuthError::MalformedHeader)?;
+        self.verify(token.trim()).map(Some)
+    }
+
+    fn verify(&self, token: &str) -> Result<CurrentUser, AuthError> {
+        let algorithm = jsonwebtoken::decode_header(token)
+            .map_err(AuthError::InvalidToken)?
+            .alg;
+        let key = match algorithm {
+            Algorithm::HS256 => self.hs256_key.as_ref(),
+            Algorithm::RS256 => self.rs256_key.as_ref(),
+            _ => None,
+        }
+        .ok_or(AuthError::UnsupportedAlgorithm(algorithm))?;
+
+        let mut validation = Validation::new(algorithm);
+        validation.validate_aud = false;
+        let claims = jsonwebtoken::decode::<Claims>(token, key, &validation)
+            .map_err(AuthError::InvalidToken)?
+            .claims;
+
+        let role = match claims.role.as_deref() {
+            None | Some("user") => Role::User,
+            Some("admin") => Role::Admin,
+            Some(role) => return Err(AuthError::UnknownRole(role.to_string())),
+        };
+
+        Ok(CurrentUser {
+            subject: claims.sub,
+            role,
+        })
+    }
+}
+
+/// Why the last request guard failed, for the `401` catcher.
+struct AuthFailure(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authentication {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, AuthError> {
+        let authenticator = request
+            .rocket()
+            .state::<Arc<Authenticator>>()
+            .expect("`Authenticator` is managed by Rocket");
+
+        match authenticator.authenticate(request.headers().get_one("Authorization")) {
+            Ok(user) => Outcome::Success(Authentication(user)),
+            Err(err) => {
+                request.local_cache(|| AuthFailure(err.to_string()));
+                Outcome::Error((Status::Unauthorized, err))
+            }
+        }
+    }
+}
+
+/// A `401` response in the same format as GraphQL errors.
+pub struct Unauthorized(String);
+
+#[catch(401)]
+pub fn unauthorized(request: &Request<'_>) -> Unauthorized {
+    let failure = request.local_cache(|| AuthFailure(Error::Unauthorized.to_string()));
+    Unauthorized(failure.0.clone())
+}
+
+impl<'r> Responder<'r, 'static> for Unauthorized {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = json!({
+            "errors": [{
+                "message": self.0,
+                "extensions": { "code": Error::Unauthorized.code() },
+            }],
+        });
+
+        let mut response =
+            (Status::Unauthorized, (ContentType::JSON, body.to_string())).respond_to(request)?;
+        response.set_header(Header::new("WWW-Authenticate", "Bearer"));
+        Ok(response)
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "Missing bearer token"),
+            AuthError::MalformedHeader => {
+                write!(f, "The `Authorization` header must be `Bearer <token>`")
+            }
+            AuthError::InvalidToken(err) => write!(f, "Invalid token: {}", err),
+            AuthError::UnsupportedAlgorithm(algorithm) => {
+                write!(f, "Tokens signed with {:?} are not accepted", algorithm)
+            }
+            AuthError::UnknownRole(role) => write!(f, "Unknown role `{}`", role),
+        }
+    }
+}
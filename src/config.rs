@@ -0,0 +1,52 @@
+use rocket::figment::Figment;
+use serde::Deserialize;
+
+/// Settings specific to this app. They're read from `Rocket.toml` so they can be configured per
+/// profile like Rocket's own settings.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfig {
+    /// The largest value clients are allowed to pass as `first` or `last` to connection fields.
+    #[serde(default = "default_max_page_size")]
+    pub max_page_size: i32,
+    /// The deepest nesting of fields allowed in a query. See `graphql::complexity`.
+    #[serde(default = "default_max_query_depth")]
+    pub max_query_depth: u32,
+    /// The highest cost allowed for a query. See `graphql::complexity`.
+    #[serde(default = "default_max_query_cost")]
+    pub max_query_cost: u64,
+    /// How many items lists without pagination, such as `users`, count as when measuring the cost
+    /// of a query. See `graphql::complexity`.
+    #[serde(default = "default_list_cost_multiplier")]
+    pub list_cost_multiplier: u64,
+    /// The most operations a batched request may contain.
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+}
+
+fn default_max_page_size() -> i32 {
+    100
+}
+
+fn default_max_query_depth() -> u32 {
+    15
+}
+
+fn default_max_query_cost() -> u64 {
+    10_000
+}
+
+fn default_list_cost_multiplier() -> u64 {
+    10
+}
+
+fn default_max_batch_size() -> usize {
+    10
+}
+
+impl AppConfig {
+    pub fn from_figment(figment: &Figment) -> Self {
+        figment
+            .extract()
+            .unwrap_or_else(|err| panic!("Invalid app config: {}", err))
+    }
+}
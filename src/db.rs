@@ -0,0 +1,96 @@
+//! The database connection pool.
+//!
+//! Diesel is blocking so connections must only be used off the async runtime, for example inside
+//! `tokio::task::spawn_blocking`.
+
+use diesel::r2d2::{ConnectionManager, Pool, PoolError, PooledConnection, State};
+use rocket::{
+    fairing::AdHoc,
+    figment::{self, Figment},
+    tokio::task,
+};
+use serde::Deserialize;
+
+mod traced;
+
+pub use traced::TracedConnection;
+
+pub type DbCon = PooledConnection<ConnectionManager<TracedConnection>>;
+
+#[derive(Clone)]
+pub struct DbPool(Pool<ConnectionManager<TracedConnection>>);
+
+/// The database used by the app, configured under `databases.<name>` in `Rocket.toml`.
+#[cfg(not(test))]
+const DATABASE: &str = "master";
+
+#[cfg(test)]
+const DATABASE: &str = "test";
+
+#[derive(Debug, Deserialize)]
+struct DatabaseConfig {
+    url: String,
+    pool_size: u32,
+}
+
+impl DbPool {
+    /// A fairing that connects to the database when Rocket ignites and manages the pool.
+    pub fn fairing() -> AdHoc {
+        AdHoc::try_on_ignite("Database pool", |rocket| async move {
+            let config = match database_config(rocket.figment()) {
+                Ok(config) => config,
+                Err(err) => {
+                    log::error!("Invalid database config: {}", err);
+                    return Err(rocket);
+                }
+            };
+
+            // Building the pool opens connections so we do it off the async runtime.
+            let pool = task::spawn_blocking(move || {
+                Pool::builder()
+                    .max_size(config.pool_size)
+                    .build(ConnectionManager::new(config.url))
+            })
+            .await;
+
+            match pool {
+                Ok(Ok(pool)) => Ok(rocket.manage(DbPool(pool))),
+                Ok(Err(err)) => {
+                    log::error!("Failed to connect to database `{}`: {}", DATABASE, err);
+                    Err(rocket)
+                }
+                Err(err) => {
+                    log::error!("Failed to connect to database `{}`: {}", DATABASE, err);
+                    Err(rocket)
+                }
+            }
+        })
+    }
+
+    /// Get a connection from the pool, waiting for one to become available. This blocks so it
+    /// must not be called on the async runtime.
+    pub fn get(&self) -> Result<DbCon, PoolError> {
+        self.0.get()
+    }
+
+    /// How many connections are open and how many of them are idle.
+    pub fn state(&self) -> State {
+        self.0.state()
+    }
+
+    /// The most connections the pool opens.
+    pub fn max_size(&self) -> u32 {
+        self.0.max_size()
+    }
+}
+
+/// The URL of the database used by the app, for connections that can't come from the pool.
+pub fn database_url(figment: &Figment) -> Result<String, Box<figment::Error>> {
+    database_config(figment).map(|config| config.url)
+}
+
+fn database_config(figment: &Figment) -> Result<DatabaseConfig, Box<figment::Error>> {
+    figment
+        .extract_inner(&format!("databases.{}", DATABASE))
+        .map_err(Box::new)
+}
@@ -0,0 +1,148 @@
+//! A Postgres connection that runs every query in a tracing span and counts it in the metrics.
+
+use crate::metrics::{metrics, table_of};
+use diesel::{
+    connection::{AnsiTransactionManager, Connection, SimpleConnection},
+    deserialize::{Queryable, QueryableByName},
+    pg::{Pg, PgConnection, PgQueryBuilder},
+    query_builder::{AsQuery, QueryBuilder, QueryFragment, QueryId},
+    result::{ConnectionResult, QueryResult},
+    sql_types::HasSqlType,
+};
+use std::{
+    any::TypeId,
+    cell::OnceCell,
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+use tracing::{field, Span};
+
+/// Wraps `PgConnection` so each query gets a `db.query` span with the SQL, without the values
+/// of bind parameters, and the number of rows returned or affected. The spans are at the `DEBUG`
+/// level since there are many of them.
+pub struct TracedConnection(PgConnection);
+
+impl TracedConnection {
+    /// The span for running `statement`, which is also counted against `table` in the metrics.
+    fn span<'a>(table: Option<&str>, statement: impl FnOnce() -> &'a str) -> Span {
+        if let Some(table) = table {
+            metrics().record_query(table);
+        }
+        let span = tracing::debug_span!(
+            "db.query",
+            db.system = "postgresql",
+            db.statement = field::Empty,
+            db.rows = field::Empty,
+        );
+        // Building the SQL isn't free, so only do it if the span is recorded.
+        if !span.is_disabled() {
+            span.record("db.statement", statement());
+        }
+        span
+    }
+
+    /// The span for running `query`. The SQL is built at most once, when the span is recorded or
+    /// when the table of a query without a static `QueryId` is needed for the metrics.
+    fn query_span<T: QueryFragment<Pg> + QueryId>(query: &T) -> Span {
+        let sql = OnceCell::new();
+        let sql = || sql.get_or_init(|| to_sql(query)).as_str();
+        let table = match T::query_id() {
+            Some(id) => cached_table(id, || table_of(sql())),
+            None => table_of(sql()).map(str::to_string),
+        };
+        Self::span(table.as_deref(), sql)
+    }
+
+    /// Run `query` in `span`, recording the number of rows if it succeeds.
+    fn run<T>(
+        span: Span,
+        rows: impl FnOnce(&T) -> usize,
+        query: impl FnOnce() -> QueryResult<T>,
+    ) -> QueryResult<T> {
+        let _entered = span.enter();
+        let result = query();
+        if let Ok(value) = &result {
+            span.record("db.rows", rows(value));
+        }
+        result
+    }
+}
+
+/// The table of queries with the static `QueryId` `id`, finding it with `table` the first time.
+/// Queries of the same type read from the same table whatever the values of their binds.
+fn cached_table<'a>(id: TypeId, table: impl FnOnce() -> Option<&'a str>) -> Option<String> {
+    static TABLES: OnceLock<Mutex<HashMap<TypeId, Option<String>>>> = OnceLock::new();
+    let mut tables = TABLES.get_or_init(Default::default).lock().unwrap();
+    tables
+        .entry(id)
+        .or_insert_with(|| table().map(str::to_string))
+        .clone()
+}
+
+/// The SQL of `query`, with `$1` and so on in place of bind parameters.
+fn to_sql<T: QueryFragment<Pg>>(query: &T) -> String {
+    let mut builder = PgQueryBuilder::default();
+    match query.to_sql(&mut builder) {
+        Ok(()) => builder.finish(),
+        Err(err) => format!("<failed to build SQL: {}>", err),
+    }
+}
+
+impl SimpleConnection for TracedConnection {
+    fn batch_execute(&self, query: &str) -> QueryResult<()> {
+        let span = Self::span(table_of(query), || query);
+        let _entered = span.enter();
+        self.0.batch_execute(query)
+    }
+}
+
+impl Connection for TracedConnection {
+    type Backend = Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        PgConnection::establish(database_url).map(TracedConnection)
+    }
+
+    fn execute(&self, query: &str) -> QueryResult<usize> {
+        let span = Self::span(table_of(query), || query);
+        Self::run(span, |rows| *rows, || self.0.execute(query))
+    }
+
+    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+    where
+        T: AsQuery,
+        T::Query: QueryFragment<Pg> + QueryId,
+        Pg: HasSqlType<T::SqlType>,
+        U: Queryable<T::SqlType, Pg>,
+    {
+        let query = source.as_query();
+        let span = Self::query_span(&query);
+        Self::run(span, Vec::len, || self.0.query_by_index(query))
+    }
+
+    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+    where
+        T: QueryFragment<Pg> + QueryId,
+        U: QueryableByName<Pg>,
+    {
+        let span = Self::query_span(source);
+        Self::run(span, Vec::len, || self.0.query_by_name(source))
+    }
+
+    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Pg> + QueryId,
+    {
+        let span = Self::query_span(source);
+        Self::run(
+            span,
+            |rows| *rows,
+            || self.0.execute_returning_count(source),
+        )
+    }
+
+    fn transaction_manager(&self) -> &AnsiTransactionManager {
+        self.0.transaction_manager()
+    }
+}
@@ -0,0 +1,153 @@
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use juniper::{graphql_value, FieldError, IntoFieldError};
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The error type used by all resolvers.
+///
+/// Each variant maps to a stable `extensions.code` so clients can handle errors without parsing
+/// messages. The details of internal errors are logged but never sent to clients since they might
+/// contain SQL or other things we don't want to leak.
+#[derive(Debug)]
+pub enum Error {
+    /// The requested record doesn't exist.
+    NotFound(String),
+    /// The client sent invalid input.
+    Validation(ValidationError),
+    /// The client isn't allowed to do what it tried to do.
+    Unauthorized,
+    /// The caller doesn't have the role required by a field.
+    Forbidden(String),
+    /// The request conflicts with the current state of the database.
+    Conflict(String),
+    /// Something went wrong on our side.
+    Internal(Box<dyn std::error::Error + Send + Sync>),
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    InvalidId(String),
+    InvalidCursor(String),
+    PageSizeOutOfRange {
+        argument: &'static str,
+        value: i32,
+        max: i32,
+    },
+    ConflictingPaginationArguments,
+    Other(String),
+}
+
+impl Error {
+    pub fn not_found(kind: &str, id: impl fmt::Display) -> Self {
+        Error::NotFound(format!("{} with id `{}` was not found", kind, id))
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        Error::Validation(ValidationError::Other(message.into()))
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotFound(_) => "NOT_FOUND",
+            Error::Validation(err) => match err {
+                ValidationError::InvalidId(_) => "INVALID_ID",
+                ValidationError::InvalidCursor(_) => "INVALID_CURSOR",
+                ValidationError::PageSizeOutOfRange { .. } => "PAGE_SIZE_OUT_OF_RANGE",
+                ValidationError::ConflictingPaginationArguments => "INVALID_PAGINATION_ARGUMENTS",
+                ValidationError::Other(_) => "BAD_USER_INPUT",
+            },
+            Error::Unauthorized => "UNAUTHORIZED",
+            Error::Forbidden(_) => "FORBIDDEN",
+            Error::Conflict(_) => "CONFLICT",
+            Error::Internal(_) => "INTERNAL_SERVER_ERROR",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(message) => write!(f, "{}", message),
+            Error::Validation(err) => write!(f, "{}", err),
+            Error::Unauthorized => write!(f, "Unauthorized"),
+            Error::Forbidden(message) => write!(f, "{}", message),
+            Error::Conflict(message) => write!(f, "{}", message),
+            Error::Internal(_) => write!(f, "Internal server error"),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InvalidId(id) => write!(f, "Invalid ID `{}`", id),
+            ValidationError::InvalidCursor(cursor) => write!(f, "Invalid cursor `{}`", cursor),
+            ValidationError::PageSizeOutOfRange {
+                argument,
+                value,
+                max,
+            } => write!(
+                f,
+                "`{}` must be between 1 and {}, got {}",
+                argument, max, value
+            ),
+            ValidationError::ConflictingPaginationArguments => {
+                write!(f, "Passing both `first` and `last` is not supported")
+            }
+            ValidationError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl IntoFieldError for Error {
+    fn into_field_error(self) -> FieldError {
+        let code = self.code();
+
+        match &self {
+            Error::Internal(err) => {
+                log::error!("Internal error while resolving GraphQL field: {}", err);
+            }
+            Error::Validation(ValidationError::PageSizeOutOfRange { max, .. }) => {
+                return FieldError::new(
+                    self.to_string(),
+                    graphql_value!({ "code": code, "min": 1, "max": (*max) }),
+                );
+            }
+            _ => {}
+        }
+
+        FieldError::new(self.to_string(), graphql_value!({ "code": code }))
+    }
+}
+
+impl From<ValidationError> for Error {
+    fn from(err: ValidationError) -> Self {
+        Error::Validation(err)
+    }
+}
+
+impl From<DieselError> for Error {
+    fn from(err: DieselError) -> Self {
+        match err {
+            // We don't know which record was missing here. Resolvers that look up records by id
+            // should use `Error::not_found` to give a better message.
+            DieselError::NotFound => Error::NotFound("Record not found".to_string()),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                Error::Conflict("A record with the same values already exists".to_string())
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                Error::Conflict("The operation references a record that doesn't exist".to_string())
+            }
+            other => Error::Internal(Box::new(other)),
+        }
+    }
+}
+
+impl From<juniper_eager_loading::Error> for Error {
+    fn from(err: juniper_eager_loading::Error) -> Self {
+        Error::Internal(Box::new(err))
+    }
+}
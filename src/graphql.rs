@@ -1,153 +1,899 @@
-use crate::{models, DbCon};
-use diesel::{pg::PgConnection, prelude::*};
-use juniper::{Executor, FieldResult, ID};
+// The generated `*Fields` traits take one argument per GraphQL argument, so connection fields
+// with filters end up with many arguments.
+#![allow(clippy::too_many_arguments)]
+
+use crate::{
+    auth::CurrentUser,
+    config::AppConfig,
+    db::{DbCon, TracedConnection},
+    error::{Error, Result, ValidationError},
+    models,
+    subscriptions::UserChange,
+};
+use diesel::{dsl::sql, prelude::*, sql_types::Text};
+use juniper::{Executor, ID};
 use juniper_eager_loading::{prelude::*, *};
 use juniper_eager_loading::{EagerLoadAllChildren, GraphqlNodeForModel};
 use juniper_from_schema::graphql_schema_from_file;
-use rocket::{
-    request::{self, FromRequest, Request},
-    Outcome,
+
+mod authorization;
+mod cache;
+pub mod complexity;
+mod connection;
+pub mod document;
+mod filter;
+pub mod global_id;
+mod search;
+
+use authorization::{rules, FieldRule};
+pub use cache::CachedRow;
+use cache::LoadCache;
+use connection::{
+    load_connection, load_partitioned_connections, total_count_mode, ConnectionArgs, Edge,
 };
+use filter::UserQueryFilter;
+use global_id::NodeType;
+use models::pagination::{partition_id, Order, SortKey};
+use std::collections::HashMap;
+
+// `build.rs` generates the `graphql_schema_from_file!` call since the schema it's given has the
+// `@auth` directives removed. The generated resolvers end in `()` expressions.
+#[allow(clippy::unused_unit)]
+mod generated {
+    use super::*;
 
-graphql_schema_from_file!("schema.graphql");
+    include!(concat!(env!("OUT_DIR"), "/schema.rs"));
+}
+pub use generated::*;
 
 pub struct Context {
     db_con: DbCon,
+    config: AppConfig,
+    current_user: Option<CurrentUser>,
+    user_change: Option<UserChange>,
+    cache: LoadCache,
 }
 
 impl juniper::Context for Context {}
 
-impl<'a, 'r> FromRequest<'a, 'r> for Context {
-    type Error = ();
+impl Context {
+    pub fn new(db_con: DbCon, config: AppConfig, current_user: Option<CurrentUser>) -> Self {
+        Context {
+            db_con,
+            config,
+            current_user,
+            user_change: None,
+            cache: LoadCache::default(),
+        }
+    }
 
-    fn from_request(request: &'a Request<'r>) -> request::Outcome<Context, ()> {
-        let db_con = request.guard::<DbCon>()?;
-        Outcome::Success(Context { db_con })
+    /// The change a subscription operation is being executed for.
+    pub fn with_user_change(self, user_change: Option<UserChange>) -> Self {
+        Context {
+            user_change,
+            ..self
+        }
     }
-}
 
-impl Context {
-    pub fn db(&self) -> &PgConnection {
-        &self.db_con.0
+    pub fn db(&self) -> &TracedConnection {
+        &self.db_con
+    }
+
+    pub fn config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    /// Rows loaded while executing this request. Mutations clear it before changing anything,
+    /// since rows loaded by earlier fields or operations might be stale afterwards.
+    pub fn cache(&self) -> &LoadCache {
+        &self.cache
+    }
+
+    /// The caller, or `None` if the request is anonymous.
+    pub fn current_user(&self) -> Option<&CurrentUser> {
+        self.current_user.as_ref()
+    }
+
+    /// Check that the caller has the role required by a field restricted with `@auth`.
+    pub fn authorize(&self, rule: FieldRule) -> Result<()> {
+        rule.check(self.current_user())
+    }
+
+    /// The change being delivered to a subscription. Fails outside of subscriptions.
+    pub fn user_change(&self) -> Result<&UserChange> {
+        self.user_change
+            .as_ref()
+            .ok_or_else(|| Error::Internal("Subscription resolved without a change".into()))
     }
 }
 
 pub struct Query;
 
 impl QueryFields for Query {
+    #[tracing::instrument(
+        name = "Query.users",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
     fn field_users(
         &self,
         executor: &Executor<'_, Context>,
         trail: &QueryTrail<'_, User, Walked>,
-    ) -> FieldResult<Vec<User>> {
+        filter: Option<UserFilter>,
+    ) -> Result<Option<Vec<User>>> {
         use crate::schema::users;
         let ctx = &executor.context();
+        ctx.authorize(rules::QUERY_USERS)?;
+        let con = &ctx.db();
+
+        let filter = UserQueryFilter::new(filter)?;
+        let user_models = filter
+            .apply(users::table.into_boxed())
+            .load::<models::User>(*con)?;
+        let users = map_models_to_graphql_nodes(&user_models, trail, ctx)?;
+
+        Ok(Some(users))
+    }
+
+    #[tracing::instrument(
+        name = "Query.user",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_user(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, User, Walked>,
+        id: ID,
+    ) -> Result<Option<User>> {
+        use crate::schema::users;
+        let ctx = &executor.context();
+        let con = &ctx.db();
+
+        let id = parse_id(&id, NodeType::User)?;
+        let user_model = ctx
+            .cache()
+            .find(&[id], |ids| {
+                users::table
+                    .filter(users::id.eq_any(ids))
+                    .load::<models::User>(*con)
+            })?
+            .pop();
+        let user = user_model
+            .map(|user_model| map_model_to_graphql_node(&user_model, trail, ctx))
+            .transpose()?;
+
+        Ok(user)
+    }
+
+    #[tracing::instrument(
+        name = "Query.countries",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_countries(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, Country, Walked>,
+    ) -> Result<Vec<Country>> {
+        use crate::schema::countries;
+        let ctx = &executor.context();
+        let con = &ctx.db();
+
+        let country_models = countries::table.load::<models::Country>(*con)?;
+        let countries = map_models_to_graphql_nodes(&country_models, trail, ctx)?;
+
+        Ok(countries)
+    }
+
+    #[tracing::instrument(
+        name = "Query.country",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_country(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, Country, Walked>,
+        id: ID,
+    ) -> Result<Option<Country>> {
+        use crate::schema::countries;
+        let ctx = &executor.context();
         let con = &ctx.db();
 
-        let user_models = users::table.load::<models::User>(*con)?;
-        let users = map_models_to_graphql_nodes(&user_models, &trail, ctx)?;
+        let id = parse_id(&id, NodeType::Country)?;
+        let country_model = ctx
+            .cache()
+            .find(&[id], |ids| {
+                countries::table
+                    .filter(countries::id.eq_any(ids))
+                    .load::<models::Country>(*con)
+            })?
+            .pop();
+        let country = country_model
+            .map(|country_model| map_model_to_graphql_node(&country_model, trail, ctx))
+            .transpose()?;
+
+        Ok(country)
+    }
+
+    #[tracing::instrument(
+        name = "Query.node",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_node(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, Node, Walked>,
+        id: ID,
+    ) -> Result<Option<Node>> {
+        let ctx = &executor.context();
+        ctx.authorize(rules::QUERY_NODE)?;
+
+        let node = load_nodes(std::slice::from_ref(&id), trail, ctx)?
+            .into_iter()
+            .next()
+            .and_then(|node| node);
 
-        Ok(users)
+        Ok(node)
     }
 
+    #[tracing::instrument(
+        name = "Query.nodes",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_nodes(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, Node, Walked>,
+        ids: Vec<ID>,
+    ) -> Result<Option<Vec<Option<Node>>>> {
+        let ctx = &executor.context();
+        ctx.authorize(rules::QUERY_NODES)?;
+
+        load_nodes(&ids, trail, ctx).map(Some)
+    }
+
+    #[tracing::instrument(
+        name = "Query.userConnections",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
     fn field_user_connections(
         &self,
         executor: &Executor<'_, Context>,
         trail: &QueryTrail<'_, UserConnection, Walked>,
         after: Option<Cursor>,
-        first: i32,
-    ) -> FieldResult<UserConnection> {
+        first: Option<i32>,
+        before: Option<Cursor>,
+        last: Option<i32>,
+        filter: Option<UserFilter>,
+        order_by: Option<Vec<UserOrder>>,
+    ) -> Result<Option<UserConnection>> {
+        use crate::schema::users;
         let ctx = &executor.context();
-        let user_connection = user_connections(after, first, trail, ctx)?;
-        Ok(user_connection)
+        ctx.authorize(rules::QUERY_USER_CONNECTIONS)?;
+
+        let filter = UserQueryFilter::new(filter)?;
+        let user_connection = load_connection(
+            filter.apply(users::table.into_boxed()),
+            user_sort_keys(order_by),
+            ConnectionArgs {
+                after,
+                first,
+                before,
+                last,
+            },
+            total_count_mode(
+                trail.total_count(),
+                || trail.total_count_args().approximate(),
+                if filter.is_empty() {
+                    Some("users")
+                } else {
+                    None
+                },
+            ),
+            trail.edges().node().walk(),
+            ctx,
+        )?;
+
+        Ok(Some(user_connection))
     }
-}
 
-fn user_connections(
-    cursor: Option<Cursor>,
-    page_size: i32,
-    trail: &QueryTrail<'_, UserConnection, Walked>,
-    ctx: &Context,
-) -> QueryResult<UserConnection> {
-    use crate::{models::pagination::*, schema::users};
+    #[tracing::instrument(
+        name = "Query.searchUsers",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_search_users(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, UserConnection, Walked>,
+        query: String,
+        first: Option<i32>,
+        after: Option<Cursor>,
+    ) -> Result<Option<UserConnection>> {
+        let ctx = &executor.context();
+        ctx.authorize(rules::QUERY_SEARCH_USERS)?;
+
+        let tsquery = search::prefix_tsquery(&query).ok_or_else(|| {
+            Error::validation("`query` must contain at least one letter or number")
+        })?;
+        let user_connection = load_connection(
+            search::matching_users(&tsquery),
+            search::rank_sort_keys(&tsquery),
+            ConnectionArgs {
+                after,
+                first,
+                before: None,
+                last: None,
+            },
+            total_count_mode(
+                trail.total_count(),
+                || trail.total_count_args().approximate(),
+                None,
+            ),
+            trail.edges().node().walk(),
+            ctx,
+        )?;
+
+        Ok(Some(user_connection))
+    }
 
-    let con = &ctx.db();
+    #[tracing::instrument(
+        name = "Query.countryConnections",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_country_connections(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, CountryConnection, Walked>,
+        after: Option<Cursor>,
+        first: Option<i32>,
+        before: Option<Cursor>,
+        last: Option<i32>,
+    ) -> Result<CountryConnection> {
+        use crate::schema::countries;
+        let ctx = &executor.context();
 
-    let page_size = i64::from(page_size);
+        let country_connection = load_connection(
+            countries::table.select(countries::all_columns),
+            vec![SortKey::column(countries::id, Order::Asc)],
+            ConnectionArgs {
+                after,
+                first,
+                before,
+                last,
+            },
+            total_count_mode(
+                trail.total_count(),
+                || trail.total_count_args().approximate(),
+                Some("countries"),
+            ),
+            trail.edges().node().walk(),
+            ctx,
+        )?;
 
-    let page_number = cursor
-        .unwrap_or_else(|| Cursor("1".to_string()))
-        .0
-        .parse::<i64>()
-        .expect("invalid cursor");
-    let next_page_cursor = Cursor((page_number + 1).to_string());
+        Ok(country_connection)
+    }
+}
 
-    let base_query = users::table.select(users::all_columns).order(users::id);
+/// Load the nodes with the given global IDs, in the same order as `ids`.
+///
+/// Nodes of the same type are loaded and eager loaded together, so this makes a constant number
+/// of queries regardless of how many IDs are given. Nodes that don't exist are `None`.
+fn load_nodes(
+    ids: &[ID],
+    trail: &QueryTrail<'_, Node, Walked>,
+    ctx: &Context,
+) -> Result<Vec<Option<Node>>> {
+    use crate::schema::{countries, users};
+    let con = ctx.db();
 
-    let (user_models, total_count) = base_query
-        .paginate(page_number)
-        .per_page(page_size)
-        .load_and_count_pages::<models::User>(con)?;
+    let ids = ids
+        .iter()
+        .map(|id| global_id::decode(id).ok_or_else(|| ValidationError::InvalidId(id.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let ids_of_type = |expected: NodeType| {
+        ids.iter()
+            .filter(|(node_type, _)| *node_type == expected)
+            .map(|(_, id)| *id)
+            .collect::<Vec<_>>()
+    };
 
-    let users = if let Some(user_trail) = trail.edges().node().walk() {
+    let user_ids = ids_of_type(NodeType::User);
+    let users = if user_ids.is_empty() {
+        HashMap::new()
+    } else {
+        let user_models = ctx.cache().find(&user_ids, |ids| {
+            users::table
+                .filter(users::id.eq_any(ids))
+                .load::<models::User>(con)
+        })?;
+        let user_trail: QueryTrail<'_, User, Walked> = trail.downcast();
         map_models_to_graphql_nodes(&user_models, &user_trail, ctx)?
+            .into_iter()
+            .map(|user| (user.user.id, user))
+            .collect()
+    };
+
+    let country_ids = ids_of_type(NodeType::Country);
+    let countries = if country_ids.is_empty() {
+        HashMap::new()
     } else {
-        vec![]
+        let country_models = ctx.cache().find(&country_ids, |ids| {
+            countries::table
+                .filter(countries::id.eq_any(ids))
+                .load::<models::Country>(con)
+        })?;
+        let country_trail: QueryTrail<'_, Country, Walked> = trail.downcast();
+        map_models_to_graphql_nodes(&country_models, &country_trail, ctx)?
+            .into_iter()
+            .map(|country| (country.country.id, country))
+            .collect()
     };
 
-    let edges = users
-        .into_iter()
-        .map(|user| Edge {
-            node: user,
-            cursor: next_page_cursor.clone(),
+    let nodes = ids
+        .iter()
+        .map(|(node_type, id)| match node_type {
+            NodeType::User => users.get(id).cloned().map(Node::from),
+            NodeType::Country => countries.get(id).cloned().map(Node::from),
         })
-        .collect::<Vec<_>>();
-
-    let page_info = PageInfo {
-        start_cursor: edges.first().map(|edge| edge.cursor.clone()),
-        end_cursor: edges.last().map(|edge| edge.cursor.clone()),
-        has_next_page: {
-            let next_page = base_query
-                .paginate(page_number + 1)
-                .per_page(1)
-                .load::<(models::User, i64)>(con)?;
-            !next_page.is_empty()
-        },
-    };
+        .collect();
 
-    Ok(UserConnection {
-        edges,
-        page_info,
-        total_count: total_count as i32,
-    })
+    Ok(nodes)
+}
+
+/// The keys to sort users by for an `orderBy` argument.
+///
+/// Users are sorted by ID last so no two users have the same keys, which keyset pagination
+/// relies on.
+fn user_sort_keys(order_by: Option<Vec<UserOrder>>) -> Vec<SortKey> {
+    use crate::schema::users;
+
+    let mut keys = Vec::new();
+    for user_order in order_by.unwrap_or_default() {
+        let order = match user_order.direction {
+            Some(OrderDirection::Desc) => Order::Desc,
+            Some(OrderDirection::Asc) | None => Order::Asc,
+        };
+
+        match user_order.field {
+            UserOrderField::Id => {
+                // IDs are unique so any keys after this one would never be compared
+                keys.push(SortKey::column(users::id, order));
+                return keys;
+            }
+            UserOrderField::Name => keys.push(SortKey::column(users::name, order)),
+            UserOrderField::CountryName => keys.push(SortKey::expression(
+                sql::<Text>(
+                    r#"(SELECT "countries"."name" FROM "countries" WHERE "countries"."id" = t."country_id")"#,
+                ),
+                order,
+            )),
+        }
+    }
+
+    keys.push(SortKey::column(users::id, Order::Asc));
+    keys
 }
 
-fn map_models_to_graphql_nodes<'a, T, M: Clone>(
+/// The models are cached, so other fields loading them by id can reuse them.
+fn map_models_to_graphql_nodes<'a, T, M: CachedRow>(
     models: &[M],
     trail: &QueryTrail<'a, T, Walked>,
     ctx: &Context,
-) -> Result<Vec<T>, diesel::result::Error>
+) -> Result<Vec<T>>
 where
-    T: EagerLoadAllChildren
-        + GraphqlNodeForModel<Model = M, Context = Context, Error = diesel::result::Error>,
+    T: EagerLoadAllChildren + GraphqlNodeForModel<Model = M, Context = Context, Error = Error>,
 {
+    ctx.cache().insert(models);
     let mut users = T::from_db_models(models);
     T::eager_load_all_children_for_each(&mut users, models, ctx, trail)?;
     Ok(users)
 }
 
+fn map_model_to_graphql_node<T, M: Clone>(
+    model: &M,
+    trail: &QueryTrail<'_, T, Walked>,
+    ctx: &Context,
+) -> Result<T>
+where
+    T: EagerLoadAllChildren + GraphqlNodeForModel<Model = M, Context = Context, Error = Error>,
+{
+    let node = T::new_from_model(model);
+    T::eager_load_all_children(node, std::slice::from_ref(model), ctx, trail)
+}
+
+/// Parse a global ID that must refer to a node of type `expected`.
+fn parse_id(id: &ID, expected: NodeType) -> Result<i32> {
+    match global_id::decode(id) {
+        Some((node_type, id)) if node_type == expected => Ok(id),
+        _ => Err(ValidationError::InvalidId(id.to_string()).into()),
+    }
+}
+
 pub struct Mutation;
 
 impl MutationFields for Mutation {
-    fn field_noop(&self, _executor: &Executor<'_, Context>) -> FieldResult<&bool> {
-        Ok(&true)
+    #[tracing::instrument(
+        name = "Mutation.createUser",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_create_user(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, User, Walked>,
+        input: CreateUserInput,
+    ) -> Result<Option<User>> {
+        use crate::schema::users;
+        let ctx = &executor.context();
+        ctx.authorize(rules::MUTATION_CREATE_USER)?;
+        ctx.cache().clear();
+        let con = &ctx.db();
+
+        let new_user = models::NewUser {
+            name: input.name,
+            country_id: parse_id(&input.country_id, NodeType::Country)?,
+        };
+
+        let user_model = diesel::insert_into(users::table)
+            .values(&new_user)
+            .get_result::<models::User>(*con)?;
+        let user = map_model_to_graphql_node(&user_model, trail, ctx)?;
+
+        Ok(Some(user))
+    }
+
+    #[tracing::instrument(
+        name = "Mutation.updateUser",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_update_user(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, User, Walked>,
+        id: ID,
+        input: UpdateUserInput,
+    ) -> Result<Option<User>> {
+        use crate::schema::users;
+        let ctx = &executor.context();
+        ctx.authorize(rules::MUTATION_UPDATE_USER)?;
+        ctx.cache().clear();
+        let con = &ctx.db();
+
+        let id = parse_id(&id, NodeType::User)?;
+        let changeset = models::UserChangeset {
+            name: input.name,
+            country_id: input
+                .country_id
+                .as_ref()
+                .map(|id| parse_id(id, NodeType::Country))
+                .transpose()?,
+        };
+
+        let user_model = if changeset.is_empty() {
+            users::table.find(id).first::<models::User>(*con)
+        } else {
+            diesel::update(users::table.find(id))
+                .set(&changeset)
+                .get_result::<models::User>(*con)
+        };
+        let user_model = user_model
+            .optional()?
+            .ok_or_else(|| Error::not_found("User", id))?;
+        let user = map_model_to_graphql_node(&user_model, trail, ctx)?;
+
+        Ok(Some(user))
+    }
+
+    #[tracing::instrument(
+        name = "Mutation.deleteUser",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_delete_user(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, User, Walked>,
+        id: ID,
+    ) -> Result<Option<User>> {
+        use crate::schema::users;
+        let ctx = &executor.context();
+        ctx.authorize(rules::MUTATION_DELETE_USER)?;
+        ctx.cache().clear();
+        let con = &ctx.db();
+
+        let id = parse_id(&id, NodeType::User)?;
+        let user_model = diesel::delete(users::table.find(id))
+            .get_result::<models::User>(*con)
+            .optional()?
+            .ok_or_else(|| Error::not_found("User", id))?;
+        let user = map_model_to_graphql_node(&user_model, trail, ctx)?;
+
+        Ok(Some(user))
+    }
+
+    #[tracing::instrument(
+        name = "Mutation.createCountry",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_create_country(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, Country, Walked>,
+        input: CreateCountryInput,
+    ) -> Result<Option<Country>> {
+        use crate::schema::countries;
+        let ctx = &executor.context();
+        ctx.authorize(rules::MUTATION_CREATE_COUNTRY)?;
+        ctx.cache().clear();
+        let con = &ctx.db();
+
+        let new_country = models::NewCountry { name: input.name };
+
+        let country_model = diesel::insert_into(countries::table)
+            .values(&new_country)
+            .get_result::<models::Country>(*con)?;
+        let country = map_model_to_graphql_node(&country_model, trail, ctx)?;
+
+        Ok(Some(country))
+    }
+
+    #[tracing::instrument(
+        name = "Mutation.renameCountry",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_rename_country(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, Country, Walked>,
+        id: ID,
+        name: String,
+    ) -> Result<Option<Country>> {
+        use crate::schema::countries;
+        let ctx = &executor.context();
+        ctx.authorize(rules::MUTATION_RENAME_COUNTRY)?;
+        ctx.cache().clear();
+        let con = &ctx.db();
+
+        let id = parse_id(&id, NodeType::Country)?;
+        let country_model = diesel::update(countries::table.find(id))
+            .set(countries::name.eq(name))
+            .get_result::<models::Country>(*con)
+            .optional()?
+            .ok_or_else(|| Error::not_found("Country", id))?;
+        let country = map_model_to_graphql_node(&country_model, trail, ctx)?;
+
+        Ok(Some(country))
+    }
+
+    #[tracing::instrument(
+        name = "Mutation.deleteCountry",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_delete_country(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, DeleteCountryResult, Walked>,
+        id: ID,
+        strategy: DeleteCountryStrategy,
+        reassign_to: Option<ID>,
+    ) -> Result<Option<DeleteCountryResult>> {
+        let ctx = &executor.context();
+        ctx.authorize(rules::MUTATION_DELETE_COUNTRY)?;
+        ctx.cache().clear();
+
+        let id = parse_id(&id, NodeType::Country)?;
+        let reassign_to = reassign_to
+            .as_ref()
+            .map(|id| parse_id(id, NodeType::Country))
+            .transpose()?;
+
+        let (outcome, country_model, affected_users_count) =
+            delete_country(id, strategy, reassign_to, ctx.db())?;
+
+        let country = if let Some(country_trail) = trail.country().walk() {
+            map_model_to_graphql_node(&country_model, &country_trail, ctx)?
+        } else {
+            Country::new_from_model(&country_model)
+        };
+
+        Ok(Some(DeleteCountryResult {
+            outcome,
+            country,
+            affected_users_count: affected_users_count as i32,
+        }))
+    }
+}
+
+/// Delete a country while keeping `users.country_id` valid.
+///
+/// Users of the country are handled according to `strategy`. Everything happens in one
+/// transaction so a failure halfway through doesn't leave users pointing at a deleted country.
+fn delete_country(
+    id: i32,
+    strategy: DeleteCountryStrategy,
+    reassign_to: Option<i32>,
+    con: &TracedConnection,
+) -> Result<(DeleteCountryOutcome, models::Country, i64)> {
+    use crate::schema::{countries, users};
+
+    if let DeleteCountryStrategy::Reassign = strategy {
+        match reassign_to {
+            None => {
+                return Err(Error::validation(
+                    "`reassignTo` is required when using the `REASSIGN` strategy",
+                ))
+            }
+            Some(other_id) if other_id == id => {
+                return Err(Error::validation(
+                    "Cannot reassign users to the country being deleted",
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+
+    con.transaction(|| {
+        let country = countries::table
+            .find(id)
+            .for_update()
+            .first::<models::Country>(con)
+            .optional()?
+            .ok_or_else(|| Error::not_found("Country", id))?;
+
+        let user_count = users::table
+            .filter(users::country_id.eq(id))
+            .count()
+            .get_result::<i64>(con)?;
+
+        let outcome = if user_count == 0 {
+            DeleteCountryOutcome::Deleted
+        } else {
+            match (strategy, reassign_to) {
+                (DeleteCountryStrategy::Reject, _) => {
+                    return Ok((DeleteCountryOutcome::Rejected, country, user_count));
+                }
+                (DeleteCountryStrategy::Reassign, Some(other_id)) => {
+                    let other_country_exists =
+                        diesel::select(diesel::dsl::exists(countries::table.find(other_id)))
+                            .get_result::<bool>(con)?;
+
+                    if !other_country_exists {
+                        return Err(Error::not_found("Country", other_id));
+                    }
+
+                    diesel::update(users::table.filter(users::country_id.eq(id)))
+                        .set(users::country_id.eq(other_id))
+                        .execute(con)?;
+                    DeleteCountryOutcome::Reassigned
+                }
+                (DeleteCountryStrategy::Reassign, None) => {
+                    unreachable!("`reassignTo` is checked before starting the transaction")
+                }
+                (DeleteCountryStrategy::Cascade, _) => {
+                    diesel::delete(users::table.filter(users::country_id.eq(id))).execute(con)?;
+                    DeleteCountryOutcome::Cascaded
+                }
+            }
+        };
+
+        diesel::delete(countries::table.find(id)).execute(con)?;
+
+        Ok((outcome, country, user_count))
+    })
+}
+
+/// The root of subscription operations.
+///
+/// Juniper can't execute subscriptions, so each subscription is executed as a query against
+/// `SubscriptionSchema` once per matching change, with the change on the `Context`. See
+/// `crate::subscriptions`.
+pub struct Subscription;
+
+pub type SubscriptionSchema = juniper::RootNode<'static, Subscription, Mutation>;
+
+impl Subscription {
+    /// Check that `current_user` may subscribe to the root field `field`. The resolvers check for
+    /// every change as well, but subscriptions that would only ever get errors are rejected when
+    /// the client subscribes.
+    pub fn authorize(field: &str, current_user: Option<&CurrentUser>) -> Result<()> {
+        match field {
+            "userCreated" => rules::SUBSCRIPTION_USER_CREATED.check(current_user),
+            "userUpdated" => rules::SUBSCRIPTION_USER_UPDATED.check(current_user),
+            _ => Ok(()),
+        }
     }
 }
 
+impl SubscriptionFields for Subscription {
+    #[tracing::instrument(
+        name = "Subscription.userCreated",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_user_created(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, User, Walked>,
+    ) -> Result<Option<User>> {
+        let ctx = executor.context();
+        ctx.authorize(rules::SUBSCRIPTION_USER_CREATED)?;
+
+        load_changed_user(ctx, trail).map(Some)
+    }
+
+    #[tracing::instrument(
+        name = "Subscription.userUpdated",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_user_updated(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, User, Walked>,
+    ) -> Result<Option<User>> {
+        let ctx = executor.context();
+        ctx.authorize(rules::SUBSCRIPTION_USER_UPDATED)?;
+
+        load_changed_user(ctx, trail).map(Some)
+    }
+
+    #[tracing::instrument(
+        name = "Subscription.userDeleted",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_user_deleted(&self, executor: &Executor<'_, Context>) -> Result<ID> {
+        let change = executor.context().user_change()?;
+        Ok(global_id::encode(NodeType::User, change.id))
+    }
+}
+
+fn load_changed_user(ctx: &Context, trail: &QueryTrail<'_, User, Walked>) -> Result<User> {
+    use crate::schema::users;
+
+    let id = ctx.user_change()?.id;
+    let user_model = users::table
+        .find(id)
+        .first::<models::User>(ctx.db())
+        .optional()?
+        .ok_or_else(|| Error::not_found("User", id))?;
+
+    map_model_to_graphql_node(&user_model, trail, ctx)
+}
+
 #[derive(Clone, Debug, EagerLoading)]
 #[eager_loading(
     model = models::User,
-    error = diesel::result::Error,
+    error = Error,
     context = Context
 )]
 pub struct User {
@@ -159,106 +905,624 @@ pub struct User {
 #[derive(Clone, Debug, EagerLoading)]
 #[eager_loading(
     model = models::Country,
-    error = diesel::result::Error,
+    error = Error,
     context = Context
 )]
 pub struct Country {
     country: models::Country,
+    #[has_many(skip)]
+    users: HasMany<User>,
+    #[has_one(skip)]
+    user_connections: HasOne<UserConnection>,
+}
+
+#[allow(missing_docs, dead_code)]
+struct EagerLoadingContextCountryForUsers;
+
+// Like the derived implementation, but the users aren't loaded for callers who may not see them.
+impl<'a> EagerLoadChildrenOfType<'a, User, EagerLoadingContextCountryForUsers> for Country {
+    type FieldArguments = ();
+
+    fn load_children(
+        models: &[models::Country],
+        field_args: &(),
+        ctx: &Context,
+    ) -> Result<LoadChildrenOutput<models::User>> {
+        // The resolver returns the error, so it ends up on the field
+        if ctx.authorize(rules::COUNTRY_USERS).is_err() {
+            return Ok(LoadChildrenOutput::ChildModels(Vec::new()));
+        }
+        let users = LoadFrom::load(models, field_args, ctx)?;
+        Ok(LoadChildrenOutput::ChildModels(users))
+    }
+
+    fn is_child_of(country: &Self, user: &User, _: &(), _: &(), _: &Context) -> bool {
+        country.country.id == user.user.country_id
+    }
+
+    fn association(country: &mut Self) -> &mut dyn Association<User> {
+        &mut country.users
+    }
+}
+
+#[allow(missing_docs, dead_code)]
+struct EagerLoadingContextCountryForUserConnections;
+
+// A page of users is loaded for every country in one query. Whether to count the users depends on
+// the trail, which `load_children` doesn't get, so this replaces `eager_load_children` instead.
+impl<'a> EagerLoadChildrenOfType<'a, UserConnection, EagerLoadingContextCountryForUserConnections>
+    for Country
+{
+    type FieldArguments = CountryUserConnectionsArgs<'a>;
+
+    fn eager_load_children(
+        nodes: &mut [Self],
+        models: &[models::Country],
+        ctx: &Context,
+        trail: &QueryTrail<'a, UserConnection, Walked>,
+        _: &Self::FieldArguments,
+    ) -> Result<()> {
+        use crate::schema::users;
+
+        // Invalid arguments, such as cursors, are left for the resolver to report so the error ends
+        // up on the field rather than on the countries
+        if ctx.authorize(rules::COUNTRY_USER_CONNECTIONS).is_err() {
+            return Ok(());
+        }
+
+        let after = optional_argument(trail, "after");
+        let first = optional_argument(trail, "first");
+        let before = optional_argument(trail, "before");
+        let last = optional_argument(trail, "last");
+        let order_by = optional_argument(trail, "orderBy");
+        let arguments = UserConnectionsArguments::new(&after, first, &before, last, &order_by);
+        let country_ids = models.iter().map(|country| country.id).collect::<Vec<_>>();
+        let user_connections = load_partitioned_connections(
+            users::table
+                .select(users::all_columns)
+                .filter(users::country_id.eq(partition_id())),
+            user_sort_keys(order_by),
+            ConnectionArgs {
+                after,
+                first,
+                before,
+                last,
+            },
+            total_count_mode(
+                trail.total_count(),
+                || trail.total_count_args().approximate(),
+                None,
+            ),
+            &country_ids,
+            trail.edges().node().walk(),
+            ctx,
+        );
+        let mut user_connections = match user_connections {
+            Ok(user_connections) => user_connections,
+            Err(Error::Validation(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        for node in nodes {
+            if let Some(mut user_connection) = user_connections.remove(&node.country.id) {
+                user_connection.eager_loaded_with = Some(arguments.clone());
+                node.user_connections.loaded_child(user_connection);
+            }
+            node.user_connections.assert_loaded_otherwise_failed();
+        }
+
+        Ok(())
+    }
+
+    // Only used by the default `eager_load_children`
+    fn load_children(
+        _: &[models::Country],
+        _: &Self::FieldArguments,
+        _: &Context,
+    ) -> Result<LoadChildrenOutput<UserConnection>> {
+        unreachable!("`Country.userConnections` is loaded by `eager_load_children`")
+    }
+
+    // Only used by the default `eager_load_children`
+    fn is_child_of(
+        _: &Self,
+        _: &UserConnection,
+        _: &(),
+        _: &Self::FieldArguments,
+        _: &Context,
+    ) -> bool {
+        unreachable!("`Country.userConnections` is loaded by `eager_load_children`")
+    }
+
+    fn association(node: &mut Self) -> &mut dyn Association<UserConnection> {
+        &mut node.user_connections
+    }
+}
+
+/// Where the field being resolved is in the query, as `line:column` like the locations of errors,
+/// recorded on resolver spans. Juniper keeps the path of the field private, but the location tells
+/// apart fields of the same type and which selection they're part of.
+fn field_location(executor: &Executor<'_, Context>) -> String {
+    let location = executor.location();
+    format!("{}:{}", location.line() + 1, location.column() + 1)
+}
+
+/// The argument `name` of the field `trail` was walked from.
+///
+/// The `*_args` methods generated by juniper-from-schema panic when a nullable argument, or a
+/// nullable field of an input object, is left out. So the arguments are read from the look ahead
+/// and converted like Juniper converts the arguments it passes to resolvers.
+fn optional_argument<T, A>(trail: &QueryTrail<'_, T, Walked>, name: &str) -> Option<A>
+where
+    A: juniper::FromInputValue,
+{
+    use juniper::LookAheadMethods;
+
+    let argument = trail
+        .look_ahead?
+        .arguments()
+        .iter()
+        .find(|argument| argument.name() == name)?;
+    A::from_input_value(&input_value(argument.value()))
+}
+
+fn input_value(
+    value: &juniper::LookAheadValue<'_, juniper::DefaultScalarValue>,
+) -> juniper::InputValue {
+    use juniper::{parser::Spanning, InputValue, LookAheadValue};
+
+    match value {
+        LookAheadValue::Null => InputValue::null(),
+        LookAheadValue::Scalar(scalar) => InputValue::scalar((*scalar).clone()),
+        LookAheadValue::Enum(name) => InputValue::enum_value(name),
+        LookAheadValue::List(values) => InputValue::list(values.iter().map(input_value).collect()),
+        LookAheadValue::Object(fields) => InputValue::Object(
+            fields
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        Spanning::unlocated(name.to_string()),
+                        Spanning::unlocated(input_value(value)),
+                    )
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Identifies the arguments `Country.userConnections` was called with.
+///
+/// A field selected several times with different arguments, using aliases, is only eager loaded
+/// with the arguments of the first selection. The others have to be loaded when resolved. Defaults
+/// are filled in, so arguments that ask for the same page are equal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserConnectionsArguments {
+    after: Option<String>,
+    first: Option<i32>,
+    before: Option<String>,
+    last: Option<i32>,
+    order_by: Vec<(UserOrderField, Order)>,
+}
+
+impl UserConnectionsArguments {
+    fn new(
+        after: &Option<Cursor>,
+        first: Option<i32>,
+        before: &Option<Cursor>,
+        last: Option<i32>,
+        order_by: &Option<Vec<UserOrder>>,
+    ) -> Self {
+        let first = match (first, last) {
+            (None, None) => Some(connection::DEFAULT_PAGE_SIZE),
+            _ => first,
+        };
+        let order_by = order_by
+            .iter()
+            .flatten()
+            .map(|user_order| {
+                let order = match user_order.direction {
+                    Some(OrderDirection::Desc) => Order::Desc,
+                    Some(OrderDirection::Asc) | None => Order::Asc,
+                };
+                (user_order.field, order)
+            })
+            .collect();
+
+        UserConnectionsArguments {
+            after: after.as_ref().map(|cursor| cursor.0.clone()),
+            first,
+            before: before.as_ref().map(|cursor| cursor.0.clone()),
+            last,
+            order_by,
+        }
+    }
 }
 
 impl UserFields for User {
-    fn field_id(&self, _: &Executor<'_, Context>) -> FieldResult<ID> {
-        Ok(ID::new(self.user.id.to_string()))
+    #[tracing::instrument(
+        name = "User.id",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_id(&self, executor: &Executor<'_, Context>) -> Result<ID> {
+        Ok(global_id::encode(NodeType::User, self.user.id))
     }
 
-    fn field_name(&self, _: &Executor<'_, Context>) -> FieldResult<&String> {
+    #[tracing::instrument(
+        name = "User.name",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_name(&self, executor: &Executor<'_, Context>) -> Result<&String> {
         Ok(&self.user.name)
     }
 
+    #[tracing::instrument(
+        name = "User.country",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
     fn field_country(
         &self,
-        _: &Executor<'_, Context>,
+        executor: &Executor<'_, Context>,
         _trail: &QueryTrail<'_, Country, Walked>,
-    ) -> FieldResult<&Country> {
+    ) -> Result<&Country> {
         Ok(self.country.try_unwrap()?)
     }
 }
 
 impl CountryFields for Country {
-    fn field_id(&self, _executor: &Executor<'_, Context>) -> FieldResult<ID> {
-        Ok(ID::new(format!("{}", self.country.id)))
+    #[tracing::instrument(
+        name = "Country.id",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_id(&self, executor: &Executor<'_, Context>) -> Result<ID> {
+        Ok(global_id::encode(NodeType::Country, self.country.id))
     }
 
-    fn field_name(&self, _executor: &Executor<'_, Context>) -> FieldResult<&String> {
+    #[tracing::instrument(
+        name = "Country.name",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_name(&self, executor: &Executor<'_, Context>) -> Result<&String> {
         Ok(&self.country.name)
     }
+
+    #[tracing::instrument(
+        name = "Country.users",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_users(
+        &self,
+        executor: &Executor<'_, Context>,
+        _trail: &QueryTrail<'_, User, Walked>,
+    ) -> Result<Option<Vec<User>>> {
+        executor.context().authorize(rules::COUNTRY_USERS)?;
+
+        Ok(Some(self.users.try_unwrap()?.clone()))
+    }
+
+    #[tracing::instrument(
+        name = "Country.userConnections",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_user_connections(
+        &self,
+        executor: &Executor<'_, Context>,
+        trail: &QueryTrail<'_, UserConnection, Walked>,
+        after: Option<Cursor>,
+        first: Option<i32>,
+        before: Option<Cursor>,
+        last: Option<i32>,
+        order_by: Option<Vec<UserOrder>>,
+    ) -> Result<Option<UserConnection>> {
+        use crate::schema::users;
+        let ctx = &executor.context();
+        ctx.authorize(rules::COUNTRY_USER_CONNECTIONS)?;
+        let country_id = self.country.id;
+
+        let arguments = UserConnectionsArguments::new(&after, first, &before, last, &order_by);
+        if let Ok(user_connection) = self.user_connections.try_unwrap() {
+            if user_connection.eager_loaded_with.as_ref() == Some(&arguments) {
+                return Ok(Some(user_connection.clone()));
+            }
+        }
+
+        let user_connection = load_connection(
+            users::table
+                .select(users::all_columns)
+                .filter(users::country_id.eq(country_id)),
+            user_sort_keys(order_by),
+            ConnectionArgs {
+                after,
+                first,
+                before,
+                last,
+            },
+            total_count_mode(
+                trail.total_count(),
+                || trail.total_count_args().approximate(),
+                None,
+            ),
+            trail.edges().node().walk(),
+            ctx,
+        )?;
+
+        Ok(Some(user_connection))
+    }
+}
+
+pub struct DeleteCountryResult {
+    outcome: DeleteCountryOutcome,
+    country: Country,
+    affected_users_count: i32,
 }
 
+impl DeleteCountryResultFields for DeleteCountryResult {
+    #[tracing::instrument(
+        name = "DeleteCountryResult.outcome",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_outcome(&self, executor: &Executor<'_, Context>) -> Result<&DeleteCountryOutcome> {
+        Ok(&self.outcome)
+    }
+
+    #[tracing::instrument(
+        name = "DeleteCountryResult.country",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_country(
+        &self,
+        executor: &Executor<'_, Context>,
+        _: &QueryTrail<'_, Country, Walked>,
+    ) -> Result<&Country> {
+        Ok(&self.country)
+    }
+
+    #[tracing::instrument(
+        name = "DeleteCountryResult.affectedUsersCount",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_affected_users_count(&self, executor: &Executor<'_, Context>) -> Result<&i32> {
+        Ok(&self.affected_users_count)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct PageInfo {
     start_cursor: Option<Cursor>,
     end_cursor: Option<Cursor>,
     has_next_page: bool,
+    has_previous_page: bool,
 }
 
 impl PageInfoFields for PageInfo {
-    fn field_start_cursor(&self, _: &Executor<'_, Context>) -> FieldResult<&Option<Cursor>> {
+    #[tracing::instrument(
+        name = "PageInfo.startCursor",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_start_cursor(&self, executor: &Executor<'_, Context>) -> Result<&Option<Cursor>> {
         Ok(&self.start_cursor)
     }
 
-    fn field_end_cursor(&self, _: &Executor<'_, Context>) -> FieldResult<&Option<Cursor>> {
+    #[tracing::instrument(
+        name = "PageInfo.endCursor",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_end_cursor(&self, executor: &Executor<'_, Context>) -> Result<&Option<Cursor>> {
         Ok(&self.end_cursor)
     }
 
-    fn field_has_next_page(&self, _: &Executor<'_, Context>) -> FieldResult<&bool> {
+    #[tracing::instrument(
+        name = "PageInfo.hasNextPage",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_has_next_page(&self, executor: &Executor<'_, Context>) -> Result<&bool> {
         Ok(&self.has_next_page)
     }
+
+    #[tracing::instrument(
+        name = "PageInfo.hasPreviousPage",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_has_previous_page(&self, executor: &Executor<'_, Context>) -> Result<&bool> {
+        Ok(&self.has_previous_page)
+    }
+}
+
+pub type UserConnection = connection::Connection<User, UserConnectionsArguments>;
+
+// `EagerLoadChildrenOfType` requires children to be nodes made from a model. A connection is made
+// from a whole page instead, and its nodes are eager loaded when it's made.
+impl GraphqlNodeForModel for UserConnection {
+    type Model = Self;
+    type Id = ();
+    type Context = Context;
+    type Error = Error;
+
+    fn new_from_model(model: &Self) -> Self {
+        model.clone()
+    }
 }
 
-pub struct UserConnection {
-    edges: Vec<UserEdge>,
-    page_info: PageInfo,
-    total_count: i32,
+impl EagerLoadAllChildren for UserConnection {
+    fn eager_load_all_children_for_each(
+        _: &mut [Self],
+        _: &[Self],
+        _: &Context,
+        _: &QueryTrail<'_, Self, Walked>,
+    ) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl UserConnectionFields for UserConnection {
+    #[tracing::instrument(
+        name = "UserConnection.edges",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
     fn field_edges(
         &self,
-        _: &Executor<'_, Context>,
+        executor: &Executor<'_, Context>,
         _: &QueryTrail<'_, UserEdge, Walked>,
-    ) -> FieldResult<&Vec<UserEdge>> {
+    ) -> Result<&Vec<UserEdge>> {
         Ok(&self.edges)
     }
 
+    #[tracing::instrument(
+        name = "UserConnection.pageInfo",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
     fn field_page_info(
         &self,
-        _: &Executor<'_, Context>,
+        executor: &Executor<'_, Context>,
         _: &QueryTrail<'_, PageInfo, Walked>,
-    ) -> FieldResult<&PageInfo> {
+    ) -> Result<&PageInfo> {
         Ok(&self.page_info)
     }
 
-    fn field_total_count(&self, _: &Executor<'_, Context>) -> FieldResult<&i32> {
-        Ok(&self.total_count)
+    #[tracing::instrument(
+        name = "UserConnection.totalCount",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_total_count(
+        &self,
+        executor: &Executor<'_, Context>,
+        _approximate: bool,
+    ) -> Result<&i32> {
+        self.total_count()
     }
 }
 
-pub struct Edge<T> {
-    node: T,
-    cursor: Cursor,
-}
-
 pub type UserEdge = Edge<User>;
 
 impl UserEdgeFields for UserEdge {
+    #[tracing::instrument(
+        name = "UserEdge.node",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
     fn field_node(
         &self,
-        _: &Executor<'_, Context>,
+        executor: &Executor<'_, Context>,
         _: &QueryTrail<'_, User, Walked>,
-    ) -> FieldResult<&User> {
+    ) -> Result<&User> {
+        Ok(&self.node)
+    }
+
+    #[tracing::instrument(
+        name = "UserEdge.cursor",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_cursor(&self, executor: &Executor<'_, Context>) -> Result<&Cursor> {
+        Ok(&self.cursor)
+    }
+}
+
+pub type CountryConnection = connection::Connection<Country>;
+
+impl CountryConnectionFields for CountryConnection {
+    #[tracing::instrument(
+        name = "CountryConnection.edges",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_edges(
+        &self,
+        executor: &Executor<'_, Context>,
+        _: &QueryTrail<'_, CountryEdge, Walked>,
+    ) -> Result<&Vec<CountryEdge>> {
+        Ok(&self.edges)
+    }
+
+    #[tracing::instrument(
+        name = "CountryConnection.pageInfo",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_page_info(
+        &self,
+        executor: &Executor<'_, Context>,
+        _: &QueryTrail<'_, PageInfo, Walked>,
+    ) -> Result<&PageInfo> {
+        Ok(&self.page_info)
+    }
+
+    #[tracing::instrument(
+        name = "CountryConnection.totalCount",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_total_count(
+        &self,
+        executor: &Executor<'_, Context>,
+        _approximate: bool,
+    ) -> Result<&i32> {
+        self.total_count()
+    }
+}
+
+pub type CountryEdge = Edge<Country>;
+
+impl CountryEdgeFields for CountryEdge {
+    #[tracing::instrument(
+        name = "CountryEdge.node",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_node(
+        &self,
+        executor: &Executor<'_, Context>,
+        _: &QueryTrail<'_, Country, Walked>,
+    ) -> Result<&Country> {
         Ok(&self.node)
     }
 
-    fn field_cursor(&self, _: &Executor<'_, Context>) -> FieldResult<&Cursor> {
+    #[tracing::instrument(
+        name = "CountryEdge.cursor",
+        level = "debug",
+        skip_all,
+        fields(graphql.location = %field_location(executor))
+    )]
+    fn field_cursor(&self, executor: &Executor<'_, Context>) -> Result<&Cursor> {
         Ok(&self.cursor)
     }
 }
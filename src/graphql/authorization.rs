@@ -0,0 +1,61 @@
+//! Field level authorization with the `@auth(requires: Role)` directive.
+//!
+//! `build.rs` generates a `FieldRule` in `rules` for each field in `schema.graphql` that uses
+//! `@auth`. The resolvers of those fields pass their rule to `Context::authorize` before doing
+//! anything else. Rules that aren't used by any resolver are dead code, so a field can't be
+//! restricted in the schema without being enforced.
+
+use super::Role;
+use crate::{
+    auth::CurrentUser,
+    error::{Error, Result},
+};
+use std::fmt;
+
+/// The rules declared with `@auth`, named after the field like `MUTATION_CREATE_USER`.
+pub mod rules {
+    use super::{FieldRule, Role};
+
+    include!(concat!(env!("OUT_DIR"), "/auth_rules.rs"));
+}
+
+/// A field that requires a role.
+#[derive(Debug, Clone, Copy)]
+pub struct FieldRule {
+    /// The field as `Type.field`.
+    pub field: &'static str,
+    pub requires: Role,
+}
+
+impl FieldRule {
+    /// Check that `current_user` has the required role.
+    pub fn check(self, current_user: Option<&CurrentUser>) -> Result<()> {
+        match current_user {
+            Some(user) if user.role.grants(self.requires) => Ok(()),
+            _ => Err(Error::Forbidden(format!(
+                "`{}` requires the {} role",
+                self.field, self.requires
+            ))),
+        }
+    }
+}
+
+impl Role {
+    /// Whether callers with this role may use fields that require `required`.
+    pub fn grants(self, required: Role) -> bool {
+        match (self, required) {
+            (Role::Admin, _) => true,
+            (Role::User, Role::User) => true,
+            (Role::User, Role::Admin) => false,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::User => write!(f, "USER"),
+            Role::Admin => write!(f, "ADMIN"),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Rows loaded while executing a request.
+//!
+//! juniper-eager-loading only avoids loading the same rows twice within one eager load. The cache
+//! lives on the `Context`, so rows loaded by one field, or one operation in a batched request, are
+//! reused by every other field:
+//!
+//! - Rows are kept in an identity map by their table and primary key. Loading rows by primary key
+//!   only queries the ones that aren't in the map yet.
+//! - Associations that aren't loaded by primary key, such as the users of countries, are
+//!   remembered by the ids they were loaded for.
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+/// Rows that can be kept in the identity map.
+pub trait CachedRow: Clone + Send + 'static {
+    /// The table the row comes from.
+    const TABLE: &'static str;
+
+    /// The primary key of the row.
+    fn id(&self) -> i32;
+}
+
+#[derive(Default)]
+pub struct LoadCache {
+    rows: RefCell<HashMap<RowKey, Box<dyn Any + Send>>>,
+    associations: RefCell<HashMap<AssociationKey, Vec<i32>>>,
+}
+
+/// A table and a primary key.
+type RowKey = (&'static str, i32);
+
+/// An association, such as `"users.country_id"`, and sorted ids without duplicates.
+type AssociationKey = (&'static str, Vec<i32>);
+
+impl LoadCache {
+    /// Put `rows` in the identity map, replacing the rows with the same primary keys.
+    pub fn insert<T: CachedRow>(&self, rows: &[T]) {
+        let mut cached = self.rows.borrow_mut();
+        for row in rows {
+            cached.insert((T::TABLE, row.id()), Box::new(row.clone()));
+        }
+    }
+
+    /// The rows with the primary keys `ids`, calling `load` with the ones that aren't cached.
+    pub fn find<T: CachedRow, E>(
+        &self,
+        ids: &[i32],
+        load: impl FnOnce(&[i32]) -> Result<Vec<T>, E>,
+    ) -> Result<Vec<T>, E> {
+        let mut rows = Vec::new();
+        let mut missing = Vec::new();
+        // Eager loading passes the id of every parent, so the same id can appear many times.
+        let mut seen = HashSet::new();
+        for &id in ids.iter().filter(|id| seen.insert(**id)) {
+            match self.get::<T>(id) {
+                Some(row) => rows.push(row),
+                None => missing.push(id),
+            }
+        }
+
+        if !missing.is_empty() {
+            let loaded = load(&missing)?;
+            self.insert(&loaded);
+            rows.extend(loaded);
+        }
+        Ok(rows)
+    }
+
+    /// The rows of `association` for `ids`. Only calls `load` if they haven't been loaded before.
+    pub fn load<T: CachedRow, E>(
+        &self,
+        association: &'static str,
+        ids: &[i32],
+        load: impl FnOnce() -> Result<Vec<T>, E>,
+    ) -> Result<Vec<T>, E> {
+        let mut ids = ids.to_vec();
+        ids.sort_unstable();
+        ids.dedup();
+        let key = (association, ids);
+
+        let cached = self.associations.borrow().get(&key).and_then(|row_ids| {
+            row_ids
+                .iter()
+                .map(|&id| self.get::<T>(id))
+                .collect::<Option<Vec<_>>>()
+        });
+        if let Some(rows) = cached {
+            return Ok(rows);
+        }
+
+        let rows = load()?;
+        self.insert(&rows);
+        let row_ids = rows.iter().map(CachedRow::id).collect();
+        self.associations.borrow_mut().insert(key, row_ids);
+        Ok(rows)
+    }
+
+    fn get<T: CachedRow>(&self, id: i32) -> Option<T> {
+        self.rows
+            .borrow()
+            .get(&(T::TABLE, id))
+            .and_then(|row| row.downcast_ref::<T>())
+            .cloned()
+    }
+
+    /// Forget everything that has been loaded.
+    pub fn clear(&self) {
+        self.rows.borrow_mut().clear();
+        self.associations.borrow_mut().clear();
+    }
+}
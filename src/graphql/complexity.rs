@@ -0,0 +1,235 @@
+//! Limits on how deep and how expensive queries may be, checked before they're executed.
+//!
+//! The depth of a query is the number of nested fields. The cost of a field is 1 plus the cost of
+//! its selections, which are counted once per requested item if the field returns many items. So
+//! `userConnections(first: 10) { edges { node { name } } }` costs 1 + 10 * 3 = 31. Connections
+//! without `first` or `last`, or with null ones, count as returning a page of the default size,
+//! `nodes` counts as returning one node per ID, and lists without pagination count as returning
+//! `list_cost_multiplier` items.
+//!
+//! Only the operation is looked at, so the complexity is measured before the document is
+//! validated. Fragments that don't exist or spread themselves are skipped, Juniper rejects those
+//! documents when validating them.
+
+use super::{
+    connection::DEFAULT_PAGE_SIZE,
+    document::{self, Operation},
+};
+use crate::config::AppConfig;
+use graphql_parser::query::{Document, Field, Selection, SelectionSet, Value as GraphQLValue};
+use juniper::InputValue;
+use serde_json::{json, Value};
+use std::fmt;
+
+/// The fields returning connections. The complexity is measured without the schema, so the fields
+/// are known by name, which is unique across types.
+const CONNECTION_FIELDS: &[&str] = &["userConnections", "countryConnections", "searchUsers"];
+
+/// The fields returning lists without pagination.
+const LIST_FIELDS: &[&str] = &["users", "countries"];
+
+/// The depth and cost of an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Complexity {
+    pub depth: u32,
+    pub cost: u64,
+}
+
+/// An operation that is too deep or too expensive to execute.
+#[derive(Debug)]
+pub struct ComplexityError {
+    complexity: Complexity,
+    max_depth: u32,
+    max_cost: u64,
+}
+
+/// Check that `operation` in `document` is within the limits in `config`.
+pub fn check(
+    document: &Document,
+    operation: &Operation<'_>,
+    variables: Option<&InputValue>,
+    config: &AppConfig,
+) -> Result<(), ComplexityError> {
+    let measurer = Measurer {
+        document,
+        operation,
+        variables,
+        config,
+    };
+    let complexity = measurer.selections(operation.selection_set, &mut Vec::new());
+
+    if complexity.depth > config.max_query_depth || complexity.cost > config.max_query_cost {
+        Err(ComplexityError {
+            complexity,
+            max_depth: config.max_query_depth,
+            max_cost: config.max_query_cost,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+struct Measurer<'a> {
+    document: &'a Document,
+    operation: &'a Operation<'a>,
+    variables: Option<&'a InputValue>,
+    config: &'a AppConfig,
+}
+
+impl<'a> Measurer<'a> {
+    /// `spreading` contains the fragments currently being spread, so cycles aren't followed
+    /// forever. Juniper rejects documents with cycles during validation.
+    fn selections(
+        &self,
+        selection_set: &'a SelectionSet,
+        spreading: &mut Vec<&'a str>,
+    ) -> Complexity {
+        let mut complexity = Complexity::default();
+        for selection in &selection_set.items {
+            let (depth, cost) = match selection {
+                Selection::Field(field) => {
+                    let children = self.selections(&field.selection_set, spreading);
+                    let multiplier = self.multiplier(field);
+                    (
+                        children.depth + 1,
+                        children.cost.saturating_mul(multiplier).saturating_add(1),
+                    )
+                }
+                Selection::FragmentSpread(spread) => {
+                    let name = spread.fragment_name.as_str();
+                    let fragment = match document::find_fragment(self.document, name) {
+                        Some(fragment) if !spreading.contains(&name) => fragment,
+                        _ => continue,
+                    };
+                    spreading.push(name);
+                    let children = self.selections(&fragment.selection_set, spreading);
+                    spreading.pop();
+                    (children.depth, children.cost)
+                }
+                Selection::InlineFragment(fragment) => {
+                    let children = self.selections(&fragment.selection_set, spreading);
+                    (children.depth, children.cost)
+                }
+            };
+            complexity.depth = complexity.depth.max(depth);
+            complexity.cost = complexity.cost.saturating_add(cost);
+        }
+        complexity
+    }
+
+    /// How many times the selections of `field` are counted.
+    fn multiplier(&self, field: &Field) -> u64 {
+        let name = field.name.as_str();
+        if CONNECTION_FIELDS.contains(&name) {
+            self.page_size(field)
+                .unwrap_or_else(|| DEFAULT_PAGE_SIZE.min(self.config.max_page_size).max(1) as u64)
+        } else if LIST_FIELDS.contains(&name) {
+            self.config.list_cost_multiplier
+        } else if name == "nodes" {
+            self.argument(field, "ids")
+                .and_then(|ids| self.list_len(ids))
+                .map_or(1, |len| len.max(1) as u64)
+        } else {
+            1
+        }
+    }
+
+    /// The largest of the `first` and `last` arguments, or `None` if neither is given, which the
+    /// resolver treats like a page of the default size. Invalid page sizes are rejected when the
+    /// field is resolved, so they're counted once.
+    fn page_size(&self, field: &Field) -> Option<u64> {
+        ["first", "last"]
+            .iter()
+            .filter_map(|name| self.int(self.argument(field, name)?))
+            .map(|value| value.max(1) as u64)
+            .max()
+    }
+
+    fn argument<'f>(&self, field: &'f Field, name: &str) -> Option<Argument<'f>> {
+        field
+            .arguments
+            .iter()
+            .find(|(argument, _)| argument == name)
+            .map(|(_, value)| Argument::Literal(value))
+    }
+
+    /// The value of an integer argument, or `None` if it's null.
+    fn int(&self, argument: Argument<'_>) -> Option<i64> {
+        match self.resolve(argument)? {
+            Argument::Literal(GraphQLValue::Int(value)) => value.as_i64(),
+            Argument::Literal(_) => None,
+            Argument::Variable(value) => value.as_scalar_value::<i32>().copied().map(i64::from),
+        }
+    }
+
+    /// The length of a list argument, or `None` if it's null. A single value is coerced to a list
+    /// of one.
+    fn list_len(&self, argument: Argument<'_>) -> Option<usize> {
+        match self.resolve(argument)? {
+            Argument::Literal(GraphQLValue::Null) => None,
+            Argument::Literal(GraphQLValue::List(values)) => Some(values.len()),
+            Argument::Literal(_) => Some(1),
+            Argument::Variable(value) if value.is_null() => None,
+            Argument::Variable(value) => {
+                Some(value.to_list_value().map_or(1, |values| values.len()))
+            }
+        }
+    }
+
+    /// Resolve a variable to the value given in the request, or else its default. Unset variables
+    /// without a default resolve to `None`.
+    fn resolve<'v>(&self, argument: Argument<'v>) -> Option<Argument<'v>>
+    where
+        'a: 'v,
+    {
+        let name = match argument {
+            Argument::Literal(GraphQLValue::Variable(name)) => name,
+            argument => return Some(argument),
+        };
+        let given = self
+            .variables
+            .and_then(|variables| variables.to_object_value())
+            .and_then(|variables| variables.get(name.as_str()).copied());
+        if let Some(value) = given {
+            return Some(Argument::Variable(value));
+        }
+        self.operation
+            .variable_definitions
+            .iter()
+            .find(|definition| &definition.name == name)?
+            .default_value
+            .as_ref()
+            .map(Argument::Literal)
+    }
+}
+
+/// The value of an argument, either from the document or from the request's variables.
+#[derive(Clone, Copy)]
+enum Argument<'a> {
+    Literal(&'a GraphQLValue),
+    Variable(&'a InputValue),
+}
+
+impl ComplexityError {
+    /// The `extensions` of the GraphQL error.
+    pub fn extensions(&self) -> Value {
+        json!({
+            "code": "QUERY_TOO_COMPLEX",
+            "depth": self.complexity.depth,
+            "cost": self.complexity.cost,
+            "maxDepth": self.max_depth,
+            "maxCost": self.max_cost,
+        })
+    }
+}
+
+impl fmt::Display for ComplexityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Query is too complex. It has a depth of {} and a cost of {}, but the maximum depth \
+             is {} and the maximum cost is {}",
+            self.complexity.depth, self.complexity.cost, self.max_depth, self.max_cost
+        )
+    }
+}
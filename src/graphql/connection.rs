@@ -0,0 +1,232 @@
+use super::{CachedRow, Context, Cursor, PageInfo};
+use crate::db::TracedConnection;
+use crate::error::{Error, Result, ValidationError};
+use crate::models::pagination::*;
+use diesel::query_dsl::methods::LoadQuery;
+use juniper_eager_loading::{EagerLoadAllChildren, GraphqlNodeForModel};
+use juniper_from_schema::{QueryTrail, Walked};
+use std::collections::HashMap;
+
+/// The page size of connection fields without `first` or `last`.
+pub(super) const DEFAULT_PAGE_SIZE: i32 = 20;
+
+/// A Relay connection of GraphQL nodes. Connections that can be eager loaded remember the
+/// arguments they were loaded with as a `K`.
+#[derive(Clone, Debug)]
+pub struct Connection<T, K = ()> {
+    pub(super) edges: Vec<Edge<T>>,
+    pub(super) page_info: PageInfo,
+    pub(super) total_count: Option<i32>,
+    /// The arguments of the field the connection was eager loaded for, if it was.
+    pub(super) eager_loaded_with: Option<K>,
+}
+
+impl<T, K> Connection<T, K> {
+    /// The total count. Fails if it wasn't loaded because `totalCount` wasn't selected.
+    pub(super) fn total_count(&self) -> Result<&i32> {
+        self.total_count
+            .as_ref()
+            .ok_or_else(|| Error::Internal("`totalCount` was resolved without being loaded".into()))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Edge<T> {
+    pub(super) node: T,
+    pub(super) cursor: Cursor,
+}
+
+/// The standard Relay pagination arguments.
+pub struct ConnectionArgs {
+    pub after: Option<Cursor>,
+    pub first: Option<i32>,
+    pub before: Option<Cursor>,
+    pub last: Option<i32>,
+}
+
+/// How to count a connection given whether `totalCount` was selected and, if so, whether the
+/// client asked for an approximate count.
+///
+/// `table` should be the table being paginated if the connection contains every row of it, which
+/// is required for estimating the count. Other connections are always counted exactly.
+pub fn total_count_mode(
+    selected: bool,
+    approximate: impl FnOnce() -> bool,
+    table: Option<&'static str>,
+) -> TotalCount {
+    if !selected {
+        return TotalCount::Skip;
+    }
+
+    match table {
+        Some(table) if approximate() => TotalCount::Estimate(table),
+        _ => TotalCount::Exact,
+    }
+}
+
+/// Load a page of `T`s from `query`.
+///
+/// Rows are sorted by `keys` and the cursors point at the values of those keys, so a cursor is
+/// only valid for the ordering it was made with. Everything except eager loading happens in a
+/// single query.
+///
+/// `node_trail` should be the trail of `edges.node`. If that isn't part of the query we skip eager
+/// loading.
+pub fn load_connection<T, M, Q, K>(
+    query: Q,
+    keys: Vec<SortKey>,
+    args: ConnectionArgs,
+    total_count: TotalCount,
+    node_trail: Option<QueryTrail<'_, T, Walked>>,
+    ctx: &Context,
+) -> Result<Connection<T, K>>
+where
+    T: EagerLoadAllChildren + GraphqlNodeForModel<Model = M, Context = Context, Error = Error>,
+    M: CachedRow,
+    Paginated<Q>: LoadQuery<TracedConnection, PageRow<M>>,
+{
+    let page = paginate(query, keys, args, total_count, ctx)?.load_page::<M>(ctx.db())?;
+    let mut connections = connections_from_pages(vec![page], node_trail, ctx)?;
+    Ok(connections.remove(0))
+}
+
+/// Load a page of `T`s from `query` for each of `partitions`, like [`load_connection`] does for a
+/// single page. `query` should only include the rows of one partition, see
+/// [`Paginated::load_partitioned_pages`].
+///
+/// Every connection is loaded with one query and the nodes of all of them are eager loaded
+/// together, so the number of queries doesn't depend on the number of partitions.
+pub fn load_partitioned_connections<T, M, Q, K>(
+    query: Q,
+    keys: Vec<SortKey>,
+    args: ConnectionArgs,
+    total_count: TotalCount,
+    partitions: &[i32],
+    node_trail: Option<QueryTrail<'_, T, Walked>>,
+    ctx: &Context,
+) -> Result<HashMap<i32, Connection<T, K>>>
+where
+    T: EagerLoadAllChildren + GraphqlNodeForModel<Model = M, Context = Context, Error = Error>,
+    M: CachedRow,
+    Partitioned<Q>: LoadQuery<TracedConnection, (i32, PageRow<M>)>,
+{
+    let pages = paginate(query, keys, args, total_count, ctx)?
+        .load_partitioned_pages::<M>(partitions, ctx.db())?;
+    let (partitions, pages): (Vec<_>, Vec<_>) = pages.into_iter().unzip();
+    let connections = connections_from_pages(pages, node_trail, ctx)?;
+    Ok(partitions.into_iter().zip(connections).collect())
+}
+
+fn paginate<Q>(
+    query: Q,
+    keys: Vec<SortKey>,
+    args: ConnectionArgs,
+    total_count: TotalCount,
+    ctx: &Context,
+) -> Result<Paginated<Q>> {
+    let (page_size, direction) = page_size_and_direction(&args, ctx)?;
+    let page_query = query.paginate_by(keys);
+    let after = decode_optional_cursor(args.after, &page_query)?;
+    let before = decode_optional_cursor(args.before, &page_query)?;
+
+    Ok(page_query
+        .after(after)
+        .before(before)
+        .direction(direction)
+        .per_page(page_size)
+        .total_count(total_count))
+}
+
+/// Make a connection of each page, eager loading the nodes of every page at once.
+fn connections_from_pages<T, M, K>(
+    mut pages: Vec<Page<M>>,
+    node_trail: Option<QueryTrail<'_, T, Walked>>,
+    ctx: &Context,
+) -> Result<Vec<Connection<T, K>>>
+where
+    T: EagerLoadAllChildren + GraphqlNodeForModel<Model = M, Context = Context, Error = Error>,
+    M: CachedRow,
+{
+    let mut page_lens = Vec::new();
+    let mut models = Vec::new();
+    let mut cursors = Vec::new();
+    for page in &mut pages {
+        page_lens.push(page.records.len());
+        for (model, values) in page.records.drain(..) {
+            models.push(model);
+            cursors.push(Cursor(encode_cursor(&values)));
+        }
+    }
+
+    ctx.cache().insert(&models);
+    let mut nodes = T::from_db_models(&models);
+    if let Some(node_trail) = node_trail {
+        T::eager_load_all_children_for_each(&mut nodes, &models, ctx, &node_trail)?;
+    }
+
+    let mut edges = nodes
+        .into_iter()
+        .zip(cursors)
+        .map(|(node, cursor)| Edge { node, cursor });
+
+    let connections = pages
+        .into_iter()
+        .zip(page_lens)
+        .map(|(page, len)| {
+            let edges = edges.by_ref().take(len).collect::<Vec<_>>();
+            let page_info = PageInfo {
+                start_cursor: edges.first().map(|edge| edge.cursor.clone()),
+                end_cursor: edges.last().map(|edge| edge.cursor.clone()),
+                has_next_page: page.has_next_page,
+                has_previous_page: page.has_previous_page,
+            };
+
+            Connection {
+                edges,
+                page_info,
+                total_count: page.total_count.map(|count| count as i32),
+                eager_loaded_with: None,
+            }
+        })
+        .collect();
+
+    Ok(connections)
+}
+
+fn page_size_and_direction(args: &ConnectionArgs, ctx: &Context) -> Result<(i64, Direction)> {
+    let max_page_size = ctx.config().max_page_size;
+
+    let (page_size, direction, arg_name) = match (args.first, args.last) {
+        (Some(_), Some(_)) => return Err(ValidationError::ConflictingPaginationArguments.into()),
+        (Some(first), None) => (first, Direction::Forward, "first"),
+        (None, Some(last)) => (last, Direction::Backward, "last"),
+        (None, None) => {
+            let page_size = DEFAULT_PAGE_SIZE.min(max_page_size);
+            return Ok((i64::from(page_size), Direction::Forward));
+        }
+    };
+
+    if page_size < 1 || page_size > max_page_size {
+        return Err(ValidationError::PageSizeOutOfRange {
+            argument: arg_name,
+            value: page_size,
+            max: max_page_size,
+        }
+        .into());
+    }
+
+    Ok((i64::from(page_size), direction))
+}
+
+fn decode_optional_cursor<Q>(
+    cursor: Option<Cursor>,
+    query: &Paginated<Q>,
+) -> Result<Option<Vec<SortValue>>> {
+    cursor
+        .map(|cursor| {
+            query
+                .decode_cursor(&cursor.0)
+                .ok_or_else(|| ValidationError::InvalidCursor(cursor.0.clone()).into())
+        })
+        .transpose()
+}
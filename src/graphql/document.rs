@@ -0,0 +1,135 @@
+//! Parsing GraphQL documents to find the operation a request executes, before it's executed.
+//!
+//! The complexity check and the routing of subscriptions both need to look at the operation before
+//! Juniper does. Juniper parses and validates the document again when executing it.
+
+use graphql_parser::query::{
+    Definition, Document, FragmentDefinition, OperationDefinition, SelectionSet, VariableDefinition,
+};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationType {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+/// The operation of a document that should be executed.
+#[derive(Debug, Clone, Copy)]
+pub struct Operation<'a> {
+    pub operation_type: OperationType,
+    pub variable_definitions: &'a [VariableDefinition],
+    pub selection_set: &'a SelectionSet,
+}
+
+/// A document that can't be executed because it's malformed or doesn't say which operation to
+/// execute.
+#[derive(Debug)]
+pub enum DocumentError {
+    Syntax(String),
+    Operation(String),
+}
+
+/// Parse the document in `query`.
+pub fn parse(query: &str) -> Result<Document, DocumentError> {
+    graphql_parser::parse_query(query)
+        .map_err(|err| DocumentError::Syntax(err.to_string().trim_end().to_string()))
+}
+
+/// Find the operation in `document` named `operation_name`, or the only operation if no name is
+/// given.
+pub fn find_operation<'a>(
+    document: &'a Document,
+    operation_name: Option<&str>,
+) -> Result<Operation<'a>, DocumentError> {
+    let mut operations = document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Operation(operation) => Some(operation),
+            Definition::Fragment(_) => None,
+        });
+
+    let operation = match operation_name {
+        Some(operation_name) => operations
+            .find(|operation| name(operation) == Some(operation_name))
+            .ok_or_else(|| {
+                DocumentError::Operation(format!("Unknown operation named `{}`", operation_name))
+            })?,
+        None => {
+            let operation = operations.next().ok_or_else(|| {
+                DocumentError::Operation("The document doesn't contain any operations".into())
+            })?;
+            if operations.next().is_some() {
+                return Err(DocumentError::Operation(
+                    "Must provide operation name if query contains multiple operations".into(),
+                ));
+            }
+            operation
+        }
+    };
+
+    Ok(match operation {
+        OperationDefinition::SelectionSet(selection_set) => Operation {
+            operation_type: OperationType::Query,
+            variable_definitions: &[],
+            selection_set,
+        },
+        OperationDefinition::Query(query) => Operation {
+            operation_type: OperationType::Query,
+            variable_definitions: &query.variable_definitions,
+            selection_set: &query.selection_set,
+        },
+        OperationDefinition::Mutation(mutation) => Operation {
+            operation_type: OperationType::Mutation,
+            variable_definitions: &mutation.variable_definitions,
+            selection_set: &mutation.selection_set,
+        },
+        OperationDefinition::Subscription(subscription) => Operation {
+            operation_type: OperationType::Subscription,
+            variable_definitions: &subscription.variable_definitions,
+            selection_set: &subscription.selection_set,
+        },
+    })
+}
+
+/// The fragment named `name` in `document`.
+pub fn find_fragment<'a>(document: &'a Document, name: &str) -> Option<&'a FragmentDefinition> {
+    document
+        .definitions
+        .iter()
+        .find_map(|definition| match definition {
+            Definition::Fragment(fragment) if fragment.name == name => Some(fragment),
+            _ => None,
+        })
+}
+
+fn name(operation: &OperationDefinition) -> Option<&str> {
+    match operation {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(query) => query.name.as_deref(),
+        OperationDefinition::Mutation(mutation) => mutation.name.as_deref(),
+        OperationDefinition::Subscription(subscription) => subscription.name.as_deref(),
+    }
+}
+
+impl DocumentError {
+    /// The `code` in the `extensions` of the GraphQL error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DocumentError::Syntax(_) => "GRAPHQL_PARSE_FAILED",
+            DocumentError::Operation(_) => "OPERATION_RESOLUTION_FAILURE",
+        }
+    }
+}
+
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocumentError::Syntax(message) | DocumentError::Operation(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
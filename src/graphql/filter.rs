@@ -0,0 +1,111 @@
+use super::{global_id::NodeType, parse_id, StringFilter, UserFilter};
+use crate::error::Result;
+use crate::schema::{countries, users};
+use diesel::{pg::Pg, prelude::*};
+
+/// A `UserFilter` that has been validated and can be applied to user queries.
+#[derive(Default)]
+pub struct UserQueryFilter {
+    name: Option<TextFilter>,
+    country_ids: Option<Vec<i32>>,
+    country_name: Option<TextFilter>,
+}
+
+impl UserQueryFilter {
+    pub fn new(filter: Option<UserFilter>) -> Result<Self> {
+        let filter = match filter {
+            Some(filter) => filter,
+            None => return Ok(UserQueryFilter::default()),
+        };
+
+        let country_ids = filter
+            .country_id_in
+            .map(|ids| {
+                ids.iter()
+                    .map(|id| parse_id(id, NodeType::Country))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+
+        Ok(UserQueryFilter {
+            name: filter.name.map(TextFilter::new),
+            country_ids,
+            country_name: filter.country_name.map(TextFilter::new),
+        })
+    }
+
+    /// Whether the filter matches every user.
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.country_ids.is_none() && self.country_name.is_none()
+    }
+
+    /// Narrow `query` to the users matching the filter.
+    pub fn apply<'a>(&self, mut query: users::BoxedQuery<'a, Pg>) -> users::BoxedQuery<'a, Pg> {
+        if let Some(name) = &self.name {
+            for pattern in name.patterns.iter().cloned() {
+                query = if name.case_insensitive {
+                    query.filter(users::name.ilike(pattern))
+                } else {
+                    query.filter(users::name.like(pattern))
+                };
+            }
+        }
+
+        if let Some(country_ids) = &self.country_ids {
+            query = query.filter(users::country_id.eq_any(country_ids.clone()));
+        }
+
+        if let Some(country_name) = &self.country_name {
+            let mut countries_query = countries::table.select(countries::id).into_boxed();
+            for pattern in country_name.patterns.iter().cloned() {
+                countries_query = if country_name.case_insensitive {
+                    countries_query.filter(countries::name.ilike(pattern))
+                } else {
+                    countries_query.filter(countries::name.like(pattern))
+                };
+            }
+            query = query.filter(users::country_id.eq_any(countries_query));
+        }
+
+        query
+    }
+}
+
+/// A `StringFilter` compiled to `LIKE` patterns which must all match.
+struct TextFilter {
+    patterns: Vec<String>,
+    case_insensitive: bool,
+}
+
+impl TextFilter {
+    fn new(filter: StringFilter) -> Self {
+        let mut patterns = Vec::new();
+        if let Some(equals) = filter.equals {
+            patterns.push(escape_like(&equals));
+        }
+        if let Some(contains) = filter.contains {
+            patterns.push(format!("%{}%", escape_like(&contains)));
+        }
+        if let Some(starts_with) = filter.starts_with {
+            patterns.push(format!("{}%", escape_like(&starts_with)));
+        }
+
+        TextFilter {
+            patterns,
+            case_insensitive: filter.case_insensitive.unwrap_or(false),
+        }
+    }
+}
+
+/// Escape the characters that have special meaning in `LIKE` patterns, so user input is matched
+/// literally.
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || c == '%' || c == '_' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
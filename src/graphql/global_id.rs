@@ -0,0 +1,45 @@
+//! Globally unique IDs for types implementing the `Node` interface.
+//!
+//! An ID is the base64 encoding of `<type>:<database id>`, so IDs of different types never
+//! collide. Clients should treat them as opaque.
+
+use juniper::ID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeType {
+    User,
+    Country,
+}
+
+impl NodeType {
+    fn name(self) -> &'static str {
+        match self {
+            NodeType::User => "User",
+            NodeType::Country => "Country",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "User" => Some(NodeType::User),
+            "Country" => Some(NodeType::Country),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode(node_type: NodeType, id: i32) -> ID {
+    ID::new(base64::encode(format!("{}:{}", node_type.name(), id)))
+}
+
+/// Decode an ID made with [`encode`]. Returns `None` if the ID is malformed.
+pub fn decode(id: &ID) -> Option<(NodeType, i32)> {
+    let bytes = base64::decode(id.as_bytes()).ok()?;
+    let decoded = String::from_utf8(bytes).ok()?;
+
+    let mut parts = decoded.splitn(2, ':');
+    let node_type = NodeType::from_name(parts.next()?)?;
+    let id = parts.next()?.parse().ok()?;
+
+    Some((node_type, id))
+}
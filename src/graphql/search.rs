@@ -0,0 +1,53 @@
+use crate::models::pagination::{Order, SortKey};
+use crate::schema::users;
+use diesel::{
+    dsl::sql,
+    pg::Pg,
+    prelude::*,
+    sql_types::{Bool, Float, Text},
+};
+
+/// Turn a search string into a `tsquery` that matches names containing a word starting with each
+/// word of `query`, so partial names match. Returns `None` if `query` doesn't contain any words.
+///
+/// Anything that isn't a letter or a number is treated as a word separator. That also means user
+/// input can never contain `tsquery` operators.
+pub fn prefix_tsquery(query: &str) -> Option<String> {
+    let words = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word))
+        .collect::<Vec<_>>();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" & "))
+    }
+}
+
+/// The users matching `tsquery`. This uses the GIN index on `users.name_tsv`.
+pub fn matching_users(tsquery: &str) -> users::BoxedQuery<'static, Pg> {
+    users::table
+        .filter(
+            sql::<Bool>("users.name_tsv @@ to_tsquery('simple', ")
+                .bind::<Text, _>(tsquery.to_string())
+                .sql(")"),
+        )
+        .into_boxed()
+}
+
+/// Sort the most relevant users first.
+///
+/// The paginated query only has the columns of `users::table` so we rank against the tsvector of
+/// `name` rather than `name_tsv`. Both are computed the same way so the ranks are equal.
+pub fn rank_sort_keys(tsquery: &str) -> Vec<SortKey> {
+    let rank = sql::<Float>("ts_rank(to_tsvector('simple', t.\"name\"), to_tsquery('simple', ")
+        .bind::<Text, _>(tsquery.to_string())
+        .sql("))");
+
+    vec![
+        SortKey::expression(rank, Order::Desc),
+        SortKey::column(users::id, Order::Asc),
+    ]
+}
@@ -0,0 +1,343 @@
+//! Reading GraphQL requests from and writing responses to HTTP, following the usual conventions
+//! for serving GraphQL over HTTP.
+//!
+//! Juniper and Diesel are both blocking, so requests are executed on Tokio's blocking thread pool
+//! rather than on the async workers handling connections. Persisted queries are resolved and
+//! queries that exceed the complexity limits are rejected before the query is executed.
+//!
+//! A `POST` body can also be a JSON array of requests. The operations in a batch are executed in
+//! order against the same `Context`, so they share a database connection and the rows cached on
+//! it, and the response is an array with the result of each operation.
+//!
+//! Every operation is counted in the metrics, along with the errors in its result.
+
+use crate::{
+    auth::CurrentUser,
+    config::AppConfig,
+    db::DbPool,
+    graphql::{complexity, document, Context, Schema},
+    metrics::metrics,
+    persisted_queries::{PersistedQueries, PersistedQuery, PersistedQueryError},
+};
+use juniper::InputValue;
+use rocket::{
+    data::{self, Data, FromData, Limits},
+    form::{self, FromForm, FromFormField, ValueField},
+    http::{ContentType, Status},
+    outcome::Outcome,
+    request::Request,
+    response::{self, Responder},
+    tokio::task,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Instant};
+use tracing::field;
+
+/// A GraphQL request from either the query string of a `GET` request or the body of a `POST`
+/// request.
+#[derive(Deserialize)]
+pub struct GraphQLRequest {
+    /// Can be left out when using a persisted query.
+    query: Option<String>,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    variables: Option<InputValue>,
+    extensions: Option<Extensions>,
+}
+
+/// The `extensions` of a request, over HTTP or WebSocket.
+#[derive(Debug, Deserialize)]
+pub struct Extensions {
+    #[serde(rename = "persistedQuery")]
+    pub persisted_query: Option<PersistedQuery>,
+}
+
+/// The query string of a `GET` request, such as `?query={users{id}}&variables={}`.
+#[derive(FromForm)]
+pub struct GraphQLQuery {
+    query: Option<String>,
+    variables: Option<JsonField<InputValue>>,
+    #[field(name = "operationName")]
+    operation_name: Option<String>,
+    extensions: Option<JsonField<Extensions>>,
+}
+
+/// A single request, or a batch of requests sent as a JSON array.
+pub enum GraphQLBatchRequest {
+    Single(GraphQLRequest),
+    Batch(Vec<GraphQLRequest>),
+}
+
+/// A form field containing JSON, such as `variables`.
+struct JsonField<T>(T);
+
+/// The JSON serialized result of executing a request.
+pub struct GraphQLResponse(Status, String);
+
+/// The result of one operation, and the status it would respond with on its own.
+struct OperationResult(Status, Value);
+
+impl GraphQLBatchRequest {
+    /// Execute the request against `schema` with a connection from `pool`, on behalf of
+    /// `current_user`.
+    ///
+    /// Operations that can't use their persisted query or are too complex get an error as their
+    /// result without affecting the rest of the batch. Fails with `503 Service Unavailable` if no
+    /// connection could be checked out.
+    pub async fn execute(
+        self,
+        schema: &Arc<Schema>,
+        pool: &DbPool,
+        config: &AppConfig,
+        persisted_queries: &Arc<PersistedQueries>,
+        current_user: Option<CurrentUser>,
+    ) -> Result<GraphQLResponse, Status> {
+        let (requests, is_batch) = match self {
+            GraphQLBatchRequest::Single(request) => (vec![request], false),
+            GraphQLBatchRequest::Batch(requests) => (requests, true),
+        };
+        if is_batch && (requests.is_empty() || requests.len() > config.max_batch_size) {
+            let message = format!(
+                "Batches must contain between 1 and {} operations, but this one contains {}",
+                config.max_batch_size,
+                requests.len()
+            );
+            let extensions = json!({ "code": "BATCH_SIZE_INVALID" });
+            return Ok(GraphQLResponse::error(
+                Status::BadRequest,
+                &message,
+                extensions,
+            ));
+        }
+
+        let schema = Arc::clone(schema);
+        let pool = pool.clone();
+        let config = config.clone();
+        let persisted_queries = Arc::clone(persisted_queries);
+
+        // Spans aren't carried over to other threads on their own.
+        let span = tracing::Span::current();
+        let result = task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let prepared = requests
+                .into_iter()
+                .map(|request| {
+                    let prepared = request.prepare(&config, &persisted_queries);
+                    if prepared.is_err() {
+                        metrics().record_rejection();
+                    }
+                    prepared
+                })
+                .collect::<Vec<_>>();
+
+            // Don't check out a connection if nothing will be executed.
+            if prepared.iter().all(Result::is_err) {
+                let results = prepared.into_iter().filter_map(Result::err).collect();
+                return Ok(GraphQLResponse::new(results, is_batch));
+            }
+
+            let db_con = pool.get().map_err(|err| {
+                log::error!("Failed to get database connection: {}", err);
+                Status::ServiceUnavailable
+            })?;
+            let context = Context::new(db_con, config, current_user);
+
+            let results = prepared
+                .into_iter()
+                .map(|prepared| {
+                    let request = match prepared {
+                        Ok(request) => request,
+                        Err(result) => return Ok(result),
+                    };
+                    let span = tracing::info_span!(
+                        "graphql.operation",
+                        graphql.operation.name = request.operation_name(),
+                        graphql.errors = field::Empty,
+                    );
+                    let _entered = span.enter();
+
+                    let start = Instant::now();
+                    let response = request.execute(&schema, &context);
+                    let duration = start.elapsed();
+                    let status = if response.is_ok() {
+                        Status::Ok
+                    } else {
+                        Status::BadRequest
+                    };
+                    let body = serde_json::to_value(&response).map_err(|err| {
+                        log::error!("Failed to serialize GraphQL response: {}", err);
+                        Status::InternalServerError
+                    })?;
+                    // Juniper responds without `data` to operations that fail validation.
+                    if body.get("data").is_some() {
+                        metrics().record_execution(request.operation_name(), duration);
+                    } else {
+                        metrics().record_rejection();
+                    }
+                    let errors = body["errors"].as_array().map_or(0, Vec::len);
+                    span.record("graphql.errors", errors);
+                    Ok(OperationResult(status, body))
+                })
+                .collect::<Result<Vec<_>, Status>>()?;
+
+            Ok(GraphQLResponse::new(results, is_batch))
+        })
+        .await;
+
+        result.unwrap_or_else(|err| {
+            log::error!("Executing GraphQL request failed: {}", err);
+            Err(Status::InternalServerError)
+        })
+    }
+}
+
+impl GraphQLRequest {
+    /// Resolve the query and check its complexity, or return the error the operation results in.
+    /// Queries sent with a new hash are only registered if they pass the checks. This blocks if
+    /// the persisted query store does.
+    fn prepare(
+        self,
+        config: &AppConfig,
+        persisted_queries: &PersistedQueries,
+    ) -> Result<juniper::http::GraphQLRequest, OperationResult> {
+        let persisted_query = self
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.persisted_query.as_ref());
+        let persisted_query_error = |err: PersistedQueryError| {
+            if let PersistedQueryError::Store(store_err) = &err {
+                log::error!("Persisted query store failed: {}", store_err);
+            }
+            let extensions = json!({ "code": err.code() });
+            OperationResult(err.status(), error_body(&err, extensions))
+        };
+        let resolved = persisted_queries
+            .resolve(self.query, persisted_query)
+            .map_err(persisted_query_error)?;
+        let query = &resolved.query;
+
+        let document_error = |err: document::DocumentError| {
+            let extensions = json!({ "code": err.code() });
+            OperationResult(Status::BadRequest, error_body(&err, extensions))
+        };
+        let document = document::parse(query).map_err(document_error)?;
+        let operation = document::find_operation(&document, self.operation_name.as_deref())
+            .map_err(document_error)?;
+        complexity::check(&document, &operation, self.variables.as_ref(), config).map_err(
+            |err| OperationResult(Status::BadRequest, error_body(&err, err.extensions())),
+        )?;
+        persisted_queries
+            .register(&resolved)
+            .map_err(persisted_query_error)?;
+
+        Ok(juniper::http::GraphQLRequest::new(
+            resolved.query,
+            self.operation_name,
+            self.variables,
+        ))
+    }
+}
+
+impl From<GraphQLQuery> for GraphQLRequest {
+    fn from(query: GraphQLQuery) -> Self {
+        GraphQLRequest {
+            query: query.query,
+            operation_name: query.operation_name,
+            variables: query.variables.map(|variables| variables.0),
+            extensions: query.extensions.map(|extensions| extensions.0),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned + Send> FromFormField<'r> for JsonField<T> {
+    fn from_value(field: ValueField<'r>) -> form::Result<'r, Self> {
+        serde_json::from_str(field.value)
+            .map(JsonField)
+            .map_err(|err| form::Error::validation(err.to_string()).into())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for GraphQLBatchRequest {
+    type Error = String;
+
+    /// Accepts a JSON object or array, or just the query with `Content-Type: application/graphql`.
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let body = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                let message = format!("Request body is larger than {}", limit);
+                return Outcome::Error((Status::PayloadTooLarge, message));
+            }
+            Err(err) => return Outcome::Error((Status::BadRequest, err.to_string())),
+        };
+
+        let is_graphql = request
+            .content_type()
+            .is_some_and(|content_type| content_type.sub() == "graphql");
+        if is_graphql {
+            let request = GraphQLRequest {
+                query: Some(body),
+                operation_name: None,
+                variables: None,
+                extensions: None,
+            };
+            return Outcome::Success(GraphQLBatchRequest::Single(request));
+        }
+
+        let request = match serde_json::from_str::<Value>(&body) {
+            Ok(Value::Array(requests)) => requests
+                .into_iter()
+                .map(serde_json::from_value)
+                .collect::<Result<_, _>>()
+                .map(GraphQLBatchRequest::Batch),
+            Ok(request) => serde_json::from_value(request).map(GraphQLBatchRequest::Single),
+            Err(err) => Err(err),
+        };
+        match request {
+            Ok(request) => Outcome::Success(request),
+            Err(err) => Outcome::Error((Status::BadRequest, err.to_string())),
+        }
+    }
+}
+
+impl GraphQLResponse {
+    /// The response to a single request, or to a batch. Batches respond with `200 OK` since each
+    /// operation has its own errors.
+    fn new(mut results: Vec<OperationResult>, is_batch: bool) -> Self {
+        for OperationResult(_, body) in &results {
+            metrics().record_errors(body);
+        }
+        if !is_batch {
+            if let Some(OperationResult(status, body)) = results.pop() {
+                return GraphQLResponse(status, body.to_string());
+            }
+        }
+        let body = Value::Array(results.into_iter().map(|result| result.1).collect());
+        GraphQLResponse(Status::Ok, body.to_string())
+    }
+
+    /// A response for a request that was rejected before being executed.
+    fn error(status: Status, message: &dyn std::fmt::Display, extensions: Value) -> Self {
+        let body = error_body(message, extensions);
+        metrics().record_errors(&body);
+        GraphQLResponse(status, body.to_string())
+    }
+}
+
+/// The body of a response with a single error.
+fn error_body(message: &dyn std::fmt::Display, extensions: Value) -> Value {
+    json!({
+        "errors": [{ "message": message.to_string(), "extensions": extensions }],
+    })
+}
+
+impl<'r> Responder<'r, 'static> for GraphQLResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let GraphQLResponse(status, body) = self;
+        (status, (ContentType::JSON, body)).respond_to(request)
+    }
+}
@@ -1,65 +1,132 @@
-#![feature(proc_macro_hygiene, decl_macro)]
-// #![deny(unused_imports, dead_code, unused_variables)]
-
 #[macro_use]
 extern crate rocket;
 #[macro_use]
 extern crate diesel;
-#[macro_use]
-extern crate rocket_contrib;
 
+mod auth;
+mod config;
+mod db;
+mod error;
 mod graphql;
+mod http;
+mod metrics;
+// Diesel's derives and `table!` implement traits inside constants.
+#[allow(non_local_definitions)]
 mod models;
+mod persisted_queries;
+#[allow(non_local_definitions)]
 mod schema;
+mod subscriptions;
+mod telemetry;
 
 #[cfg(test)]
 mod tests;
 
-use crate::graphql::*;
-use rocket::{response::content, Rocket, State};
-
-#[cfg(not(test))]
-#[database("master")]
-struct DbCon(diesel::PgConnection);
-
-#[cfg(test)]
-#[database("test")]
-struct DbCon(diesel::PgConnection);
+use crate::{
+    auth::{Authentication, Authenticator},
+    config::AppConfig,
+    db::DbPool,
+    graphql::*,
+    http::{GraphQLBatchRequest, GraphQLQuery, GraphQLRequest, GraphQLResponse},
+    persisted_queries::PersistedQueries,
+    subscriptions::{GraphQLWebSocket, Session, UserChanges},
+};
+use rocket::{
+    figment::Figment,
+    http::{ContentType, Status},
+    response::content::RawHtml,
+    Build, Rocket, State,
+};
+use std::sync::Arc;
 
 #[get("/graphiql")]
-fn graphiql() -> content::Html<String> {
-    juniper_rocket::graphiql_source("/graphql")
+fn graphiql() -> RawHtml<String> {
+    RawHtml(juniper::http::graphiql::graphiql_source("/graphql"))
+}
+
+/// Metrics in Prometheus' text format.
+#[get("/metrics")]
+fn metrics_handler(pool: &State<DbPool>) -> (ContentType, String) {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (content_type, metrics::metrics().render(pool))
 }
 
-#[get("/graphql?<request>")]
-fn get_graphql_handler(
-    context: Context,
-    request: juniper_rocket::GraphQLRequest,
-    schema: State<Schema>,
-) -> juniper_rocket::GraphQLResponse {
-    request.execute(&schema, &context)
+#[get("/graphql?<request..>")]
+#[tracing::instrument(name = "GET /graphql", skip_all)]
+async fn get_graphql_handler(
+    request: GraphQLQuery,
+    auth: Authentication,
+    schema: &State<Arc<Schema>>,
+    pool: &State<DbPool>,
+    config: &State<AppConfig>,
+    persisted_queries: &State<Arc<PersistedQueries>>,
+) -> Result<GraphQLResponse, Status> {
+    GraphQLBatchRequest::Single(GraphQLRequest::from(request))
+        .execute(schema, pool, config, persisted_queries, auth.0)
+        .await
 }
 
 #[post("/graphql", data = "<request>")]
-fn post_graphql_handler(
-    context: Context,
-    request: juniper_rocket::GraphQLRequest,
-    schema: State<Schema>,
-) -> juniper_rocket::GraphQLResponse {
-    request.execute(&schema, &context)
+#[tracing::instrument(name = "POST /graphql", skip_all)]
+async fn post_graphql_handler(
+    request: GraphQLBatchRequest,
+    auth: Authentication,
+    schema: &State<Arc<Schema>>,
+    pool: &State<DbPool>,
+    config: &State<AppConfig>,
+    persisted_queries: &State<Arc<PersistedQueries>>,
+) -> Result<GraphQLResponse, Status> {
+    request
+        .execute(schema, pool, config, persisted_queries, auth.0)
+        .await
+}
+
+#[get("/graphql/ws")]
+fn graphql_ws(ws: rocket_ws::WebSocket, session: Session) -> GraphQLWebSocket {
+    session.accept(ws)
 }
 
 fn main() {
     dotenv::dotenv().ok();
-    rocket().launch();
+    // Dropped after Rocket shuts down, so the last spans are exported.
+    let _telemetry = match telemetry::init(&rocket::Config::figment()) {
+        Ok(telemetry) => telemetry,
+        Err(err) => {
+            eprintln!("Invalid telemetry config: {}", err);
+            std::process::exit(1);
+        }
+    };
+    // Rocket logs why launching failed when the error is dropped.
+    let _ = rocket::execute(rocket().launch());
 }
 
-fn rocket() -> Rocket {
-    rocket::ignite()
-        .manage(Schema::new(Query, Mutation))
+fn rocket() -> Rocket<Build> {
+    rocket_from_figment(rocket::Config::figment())
+}
+
+/// The app configured from `figment` rather than just `Rocket.toml` and the environment.
+fn rocket_from_figment(figment: Figment) -> Rocket<Build> {
+    let rocket = rocket::custom(figment);
+    let app_config = AppConfig::from_figment(rocket.figment());
+    let authenticator = Authenticator::from_figment(rocket.figment());
+
+    rocket
+        .manage(Arc::new(Schema::new(Query, Mutation)))
+        .manage(Arc::new(SubscriptionSchema::new(Subscription, Mutation)))
+        .manage(app_config)
+        .manage(Arc::new(authenticator))
         .mount(
             "/",
-            routes![graphiql, get_graphql_handler, post_graphql_handler],
+            routes![
+                graphiql,
+                metrics_handler,
+                get_graphql_handler,
+                post_graphql_handler,
+                graphql_ws
+            ],
         )
-        .attach(DbCon::fairing())
+        .register("/", catchers![auth::unauthorized])
+        .attach(DbPool::fairing())
+        .attach(PersistedQueries::fairing())
+        .attach(UserChanges::fairing())
 }
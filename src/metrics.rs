@@ -0,0 +1,241 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! The metrics are global since SQL queries are counted by `TracedConnection`, which has no access
+//! to Rocket's state. The database pool is only looked at when the metrics are scraped.
+
+use crate::db::DbPool;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+pub struct Metrics {
+    registry: Registry,
+    /// `graphql_requests_total{operation}`. Operations in a batch are counted separately, and
+    /// operations that weren't executed are counted as `other`.
+    requests: IntCounterVec,
+    operation_labels: OperationLabels,
+    /// `graphql_request_duration_seconds{operation}`. Only executed operations are timed.
+    request_duration: HistogramVec,
+    /// `graphql_errors_total{code}`, by the `extensions.code` of errors in responses.
+    errors: IntCounterVec,
+    /// `db_queries_total{table}`.
+    queries: IntCounterVec,
+    pool_connections: IntGauge,
+    pool_idle_connections: IntGauge,
+    pool_max_size: IntGauge,
+}
+
+/// The label of operations sent without an `operationName`.
+const ANONYMOUS: &str = "anonymous";
+
+/// The label of operations that were rejected rather than executed. Clients can send any name, so
+/// only the names of operations that were valid enough to execute are used as labels, and only up
+/// to `MAX_OPERATION_LABELS` of them.
+const OTHER: &str = "other";
+
+/// How many operation names are used as labels. Operations with other names are counted as
+/// `other`.
+const MAX_OPERATION_LABELS: usize = 100;
+
+/// The code of errors without `extensions.code`, like the ones Juniper reports for invalid
+/// queries.
+const UNKNOWN_CODE: &str = "UNKNOWN";
+
+/// The operation names used as labels. Clients choose the names, so only the first names seen
+/// become labels, which keeps the number of series bounded.
+pub struct OperationLabels {
+    capacity: usize,
+    names: Mutex<HashSet<String>>,
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "graphql_requests_total",
+                "GraphQL operations received, by operation name or `other` if rejected",
+            ),
+            &["operation"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "graphql_request_duration_seconds",
+                "Time spent executing GraphQL operations, by operation name",
+            ),
+            &["operation"],
+        )
+        .unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new("graphql_errors_total", "GraphQL errors returned, by code"),
+            &["code"],
+        )
+        .unwrap();
+        let queries = IntCounterVec::new(
+            Opts::new("db_queries_total", "SQL queries run, by table"),
+            &["table"],
+        )
+        .unwrap();
+        let pool_connections = IntGauge::new(
+            "db_pool_connections",
+            "Connections in the database pool, both idle and in use",
+        )
+        .unwrap();
+        let pool_idle_connections = IntGauge::new(
+            "db_pool_idle_connections",
+            "Idle connections in the database pool",
+        )
+        .unwrap();
+        let pool_max_size = IntGauge::new(
+            "db_pool_max_size",
+            "The most connections the database pool opens",
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(queries.clone())).unwrap();
+        registry
+            .register(Box::new(pool_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pool_idle_connections.clone()))
+            .unwrap();
+        registry.register(Box::new(pool_max_size.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            operation_labels: OperationLabels::new(MAX_OPERATION_LABELS),
+            request_duration,
+            errors,
+            queries,
+            pool_connections,
+            pool_idle_connections,
+            pool_max_size,
+        }
+    }
+
+    /// Count an operation that was executed, and how long that took.
+    pub fn record_execution(&self, operation: Option<&str>, duration: Duration) {
+        let operation = self.operation_labels.label(operation);
+        self.requests.with_label_values(&[operation]).inc();
+        self.request_duration
+            .with_label_values(&[operation])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Count an operation that was rejected before or by Juniper's validation.
+    pub fn record_rejection(&self) {
+        self.requests.with_label_values(&[OTHER]).inc();
+    }
+
+    /// Count the errors in the response `body` of an operation.
+    pub fn record_errors(&self, body: &Value) {
+        let errors = match body["errors"].as_array() {
+            Some(errors) => errors,
+            None => return,
+        };
+        for error in errors {
+            let code = error["extensions"]["code"].as_str().unwrap_or(UNKNOWN_CODE);
+            self.errors.with_label_values(&[code]).inc();
+        }
+    }
+
+    /// Count a SQL query against the table it reads from or writes to. See `table_of`.
+    pub fn record_query(&self, table: &str) {
+        self.queries.with_label_values(&[table]).inc();
+    }
+
+    /// The metrics in Prometheus' text format.
+    pub fn render(&self, pool: &DbPool) -> String {
+        let state = pool.state();
+        self.pool_connections.set(state.connections.into());
+        self.pool_idle_connections
+            .set(state.idle_connections.into());
+        self.pool_max_size.set(pool.max_size().into());
+
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_else(|err| {
+                log::error!("Failed to encode metrics: {}", err);
+                String::new()
+            })
+    }
+}
+
+impl OperationLabels {
+    pub fn new(capacity: usize) -> Self {
+        OperationLabels {
+            capacity,
+            names: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The label of an operation named `operation`, `anonymous` if it has no name or `other` once
+    /// there are too many names.
+    pub fn label<'a>(&self, operation: Option<&'a str>) -> &'a str {
+        let operation = match operation {
+            Some(operation) => operation,
+            None => return ANONYMOUS,
+        };
+        let mut names = self.names.lock().unwrap_or_else(|err| err.into_inner());
+        if names.contains(operation) {
+            operation
+        } else if names.len() < self.capacity {
+            names.insert(operation.to_string());
+            operation
+        } else {
+            OTHER
+        }
+    }
+}
+
+/// The first table after `FROM`, `INTO` or `UPDATE` in SQL generated by Diesel, which quotes
+/// table names. Tables in subqueries of expressions, such as the sort keys of paginated queries,
+/// are skipped, but paginated queries select from subqueries in their `FROM` clause, so they're
+/// counted against the table being paginated.
+pub fn table_of(sql: &str) -> Option<&str> {
+    // Whether each open parenthesis is a subquery in a `FROM` clause rather than an expression.
+    let mut parens = Vec::new();
+    for (index, c) in sql.char_indices() {
+        match c {
+            '(' => {
+                let before = sql[..index].trim_end();
+                let in_from = ["FROM", "JOIN", "LATERAL"]
+                    .iter()
+                    .any(|keyword| before.ends_with(keyword));
+                parens.push(in_from);
+            }
+            ')' => {
+                parens.pop();
+            }
+            _ if parens.iter().all(|in_from| *in_from) => {
+                for keyword in &["FROM \"", "INTO \"", "UPDATE \""] {
+                    if sql[index..].starts_with(keyword) {
+                        let start = index + keyword.len();
+                        let len = sql[start..].find('"')?;
+                        return Some(&sql[start..start + len]);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
@@ -1,9 +1,10 @@
 pub mod pagination;
 
-use crate::graphql::Context;
+use crate::error::Error;
+use crate::graphql::{CachedRow, Context};
 use crate::schema::*;
 use diesel::prelude::*;
-use juniper_eager_loading::impl_load_from_for_diesel_pg;
+use juniper_eager_loading::LoadFrom;
 
 #[derive(Queryable, Debug, Clone)]
 pub struct User {
@@ -12,18 +13,100 @@ pub struct User {
     pub country_id: i32,
 }
 
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "users"]
+pub struct NewUser {
+    pub name: String,
+    pub country_id: i32,
+}
+
+#[derive(AsChangeset, Debug, Clone)]
+#[table_name = "users"]
+pub struct UserChangeset {
+    pub name: Option<String>,
+    pub country_id: Option<i32>,
+}
+
+impl UserChangeset {
+    /// Diesel refuses to run an `UPDATE` without any columns to set.
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.country_id.is_none()
+    }
+}
+
 #[derive(Queryable, Debug, Clone)]
 pub struct Country {
     pub id: i32,
     pub name: String,
 }
 
-impl_load_from_for_diesel_pg! {
-    (
-        error = diesel::result::Error,
-        context = Context,
-    ) => {
-        i32 -> (users, User),
-        i32 -> (countries, Country),
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "countries"]
+pub struct NewCountry {
+    pub name: String,
+}
+
+impl CachedRow for User {
+    const TABLE: &'static str = "users";
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+}
+
+impl CachedRow for Country {
+    const TABLE: &'static str = "countries";
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+}
+
+// These are what `impl_load_from_for_diesel_pg!` would generate, except that the rows go through
+// the request's cache.
+
+impl LoadFrom<i32> for User {
+    type Error = Error;
+    type Context = Context;
+
+    fn load(ids: &[i32], _: &(), ctx: &Context) -> Result<Vec<Self>, Error> {
+        ctx.cache().find(ids, |ids| {
+            users::table
+                .filter(users::id.eq_any(ids))
+                .load::<User>(ctx.db())
+                .map_err(From::from)
+        })
+    }
+}
+
+impl LoadFrom<i32> for Country {
+    type Error = Error;
+    type Context = Context;
+
+    fn load(ids: &[i32], _: &(), ctx: &Context) -> Result<Vec<Self>, Error> {
+        ctx.cache().find(ids, |ids| {
+            countries::table
+                .filter(countries::id.eq_any(ids))
+                .load::<Country>(ctx.db())
+                .map_err(From::from)
+        })
+    }
+}
+
+impl LoadFrom<Country> for User {
+    type Error = Error;
+    type Context = Context;
+
+    fn load(countries: &[Country], _: &(), ctx: &Context) -> Result<Vec<Self>, Error> {
+        let country_ids = countries
+            .iter()
+            .map(|country| country.id)
+            .collect::<Vec<_>>();
+        ctx.cache().load("users.country_id", &country_ids, || {
+            users::table
+                .filter(users::country_id.eq_any(&country_ids))
+                .load::<User>(ctx.db())
+                .map_err(From::from)
+        })
     }
 }
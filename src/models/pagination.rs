@@ -1,30 +1,303 @@
+use crate::db::TracedConnection;
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
 use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel::query_builder::*;
 use diesel::query_dsl::methods::LoadQuery;
-use diesel::sql_types::BigInt;
+use diesel::sql_types::{Array, BigInt, Bool, Float, Integer, Nullable, Text};
+use serde_json::Value;
+use std::{collections::HashMap, convert::TryFrom, rc::Rc};
 
+/// Keyset pagination.
+///
+/// Rather than skipping rows with `OFFSET` we only return rows that sort after the `after` cursor
+/// and before the `before` cursor. That keeps deep pages as fast as the first one (given an index
+/// on the sort keys) and rows being inserted or deleted don't shift results between pages.
+///
+/// Rows are sorted by `keys` in order. The last key should be unique, otherwise rows with equal
+/// keys might be skipped or repeated across pages.
 pub trait Paginate: Sized {
-    fn paginate(self, page: i64) -> Paginated<Self>;
+    fn paginate_by(self, keys: Vec<SortKey>) -> Paginated<Self>;
 }
 
 impl<T> Paginate for T {
-    fn paginate(self, page: i64) -> Paginated<Self> {
+    fn paginate_by(self, keys: Vec<SortKey>) -> Paginated<Self> {
         Paginated {
             query: self,
+            keys,
+            after: None,
+            before: None,
+            direction: Direction::Forward,
             per_page: DEFAULT_PER_PAGE,
-            page,
+            total_count: TotalCount::Skip,
         }
     }
 }
 
 const DEFAULT_PER_PAGE: i64 = 10;
 
-#[derive(Debug, Clone, Copy, QueryId)]
+/// Which end of the rows between `after` and `before` a page is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The first `per_page` rows.
+    Forward,
+    /// The last `per_page` rows.
+    Backward,
+}
+
+/// How to compute the total number of rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TotalCount {
+    /// Don't count. Counting requires looking at every row so skip it when it isn't needed.
+    Skip,
+    /// Count every row.
+    Exact,
+    /// Use the row estimate Postgres keeps for the given table. This is much faster than counting
+    /// large tables but only correct when the query contains every row of the table, and only as
+    /// precise as the table statistics. Falls back to counting if the table has no statistics yet.
+    Estimate(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl Order {
+    fn reverse(self) -> Self {
+        match self {
+            Order::Asc => Order::Desc,
+            Order::Desc => Order::Asc,
+        }
+    }
+}
+
+/// Something rows can be sorted by.
+#[derive(Clone)]
+pub struct SortKey {
+    expression: SortExpression,
+    kind: SortValueKind,
+    order: Order,
+}
+
+#[derive(Clone)]
+enum SortExpression {
+    Column(&'static str),
+    Expression(Rc<dyn QueryFragment<Pg>>),
+}
+
+impl SortKey {
+    /// Sort by a column of the paginated query.
+    pub fn column<C>(_column: C, order: Order) -> Self
+    where
+        C: Column,
+        C::SqlType: SortSqlType,
+    {
+        SortKey {
+            expression: SortExpression::Column(C::NAME),
+            kind: C::SqlType::KIND,
+            order,
+        }
+    }
+
+    /// Sort by an arbitrary expression, usually made with [`diesel::dsl::sql`]. The paginated
+    /// query is aliased `t` so the expression can refer to its columns with `t."column"`.
+    pub fn expression<E>(expression: E, order: Order) -> Self
+    where
+        E: Expression + QueryFragment<Pg> + 'static,
+        E::SqlType: SortSqlType,
+    {
+        SortKey {
+            expression: SortExpression::Expression(Rc::new(expression)),
+            kind: E::SqlType::KIND,
+            order,
+        }
+    }
+
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        match &self.expression {
+            SortExpression::Column(name) => {
+                out.push_sql("t.");
+                out.push_identifier(name)
+            }
+            SortExpression::Expression(expression) => expression.walk_ast(out),
+        }
+    }
+}
+
+/// The value of a sort key for some row. Cursors are made from these.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortValue {
+    Int(i32),
+    Float(f32),
+    Text(String),
+}
+
+impl SortValue {
+    fn from_json(value: Value, kind: SortValueKind) -> Option<Self> {
+        match (value, kind) {
+            (Value::Number(number), SortValueKind::Int) => {
+                i32::try_from(number.as_i64()?).ok().map(SortValue::Int)
+            }
+            (Value::Number(number), SortValueKind::Float) => {
+                Some(SortValue::Float(number.as_f64()? as f32))
+            }
+            (Value::String(string), SortValueKind::Text) => Some(SortValue::Text(string)),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            SortValue::Int(int) => Value::from(*int),
+            SortValue::Float(float) => Value::from(f64::from(*float)),
+            SortValue::Text(text) => Value::from(text.as_str()),
+        }
+    }
+
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        match self {
+            SortValue::Int(int) => out.push_bind_param::<Integer, _>(int),
+            SortValue::Float(float) => out.push_bind_param::<Float, _>(float),
+            SortValue::Text(text) => out.push_bind_param::<Text, _>(text),
+        }
+    }
+}
+
+impl From<i32> for SortValue {
+    fn from(int: i32) -> Self {
+        SortValue::Int(int)
+    }
+}
+
+impl From<String> for SortValue {
+    fn from(text: String) -> Self {
+        SortValue::Text(text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortValueKind {
+    Int,
+    Float,
+    Text,
+}
+
+/// A record together with the values of its sort keys.
+pub type KeyedRecord<U> = (U, Vec<SortValue>);
+
+/// A page loaded with [`Paginated::load_page`].
+#[derive(Debug)]
+pub struct Page<U> {
+    /// The records of the page. The sort key values can be turned into a cursor with
+    /// [`encode_cursor`].
+    pub records: Vec<KeyedRecord<U>>,
+    /// The number of records regardless of the cursors. `None` if counting was skipped.
+    pub total_count: Option<i64>,
+    pub has_next_page: bool,
+    pub has_previous_page: bool,
+}
+
+/// A record with the total count, whether there are rows before `after` and after `before`, and
+/// the sort key values as JSON.
+pub type PageRow<U> = (U, Option<i64>, bool, bool, String);
+
+/// What's needed to turn the rows loaded for a page into a [`Page`].
+struct PageShape {
+    keys: Vec<SortKey>,
+    direction: Direction,
+    per_page: i64,
+    count_skipped: bool,
+}
+
+impl PageShape {
+    fn of<T>(paginated: &Paginated<T>) -> Self {
+        PageShape {
+            keys: paginated.keys.clone(),
+            direction: paginated.direction,
+            per_page: paginated.per_page,
+            count_skipped: paginated.total_count == TotalCount::Skip,
+        }
+    }
+
+    /// Make a page from at most `per_page + 1` rows in the order they were queried in.
+    fn page<U>(&self, rows: Vec<PageRow<U>>) -> QueryResult<Page<U>> {
+        let total_count = match rows.first() {
+            Some(first) => first.1,
+            None if self.count_skipped => None,
+            // Without any rows we don't need a count to know there are none
+            None => Some(0),
+        };
+        let has_records_before_after = rows.first().map(|x| x.2).unwrap_or(false);
+        let has_records_after_before = rows.first().map(|x| x.3).unwrap_or(false);
+        let has_more = rows.len() as i64 > self.per_page;
+
+        let mut records = rows
+            .into_iter()
+            .take(self.per_page as usize)
+            .map(|(record, _, _, _, json)| {
+                let values = serde_json::from_str::<Vec<Value>>(&json)
+                    .ok()
+                    .and_then(|values| sort_values_from_json(values, &self.keys))
+                    .ok_or_else(|| {
+                        diesel::result::Error::DeserializationError(
+                            format!("invalid sort keys `{}`", json).into(),
+                        )
+                    })?;
+                Ok((record, values))
+            })
+            .collect::<QueryResult<Vec<_>>>()?;
+        if self.direction == Direction::Backward {
+            records.reverse();
+        }
+
+        let (has_next_page, has_previous_page) = match self.direction {
+            Direction::Forward => (
+                has_more || has_records_after_before,
+                has_records_before_after,
+            ),
+            Direction::Backward => (
+                has_records_after_before,
+                has_more || has_records_before_after,
+            ),
+        };
+
+        Ok(Page {
+            records,
+            total_count,
+            has_next_page,
+            has_previous_page,
+        })
+    }
+}
+
+/// SQL types that can be used as sort keys.
+pub trait SortSqlType {
+    const KIND: SortValueKind;
+}
+
+impl SortSqlType for Integer {
+    const KIND: SortValueKind = SortValueKind::Int;
+}
+
+impl SortSqlType for Float {
+    const KIND: SortValueKind = SortValueKind::Float;
+}
+
+impl SortSqlType for Text {
+    const KIND: SortValueKind = SortValueKind::Text;
+}
+
 pub struct Paginated<T> {
     query: T,
-    page: i64,
+    keys: Vec<SortKey>,
+    after: Option<Vec<SortValue>>,
+    before: Option<Vec<SortValue>>,
+    direction: Direction,
     per_page: i64,
+    total_count: TotalCount,
 }
 
 impl<T> Paginated<T> {
@@ -32,35 +305,308 @@ impl<T> Paginated<T> {
         Paginated { per_page, ..self }
     }
 
-    pub fn load_and_count_pages<U>(self, conn: &PgConnection) -> QueryResult<(Vec<U>, i64)>
+    /// Only include rows after `after`, which must be values for the keys of this query.
+    pub fn after(self, after: Option<Vec<SortValue>>) -> Self {
+        Paginated { after, ..self }
+    }
+
+    /// Only include rows before `before`, which must be values for the keys of this query.
+    pub fn before(self, before: Option<Vec<SortValue>>) -> Self {
+        Paginated { before, ..self }
+    }
+
+    pub fn direction(self, direction: Direction) -> Self {
+        Paginated { direction, ..self }
+    }
+
+    pub fn total_count(self, total_count: TotalCount) -> Self {
+        Paginated {
+            total_count,
+            ..self
+        }
+    }
+
+    /// Decode a cursor made with [`encode_cursor`]. Returns `None` if the cursor is malformed or
+    /// was made for different keys.
+    pub fn decode_cursor(&self, cursor: &str) -> Option<Vec<SortValue>> {
+        let bytes = base64::decode(cursor).ok()?;
+        let values = serde_json::from_slice::<Vec<Value>>(&bytes).ok()?;
+        sort_values_from_json(values, &self.keys)
+    }
+
+    /// Load a page of records. Records are always returned in sort order regardless of the
+    /// direction.
+    ///
+    /// Everything is loaded with a single query. We fetch one record more than `per_page` to know
+    /// whether there are more records in the direction we're paginating.
+    pub fn load_page<U>(self, conn: &TracedConnection) -> QueryResult<Page<U>>
     where
-        Self: LoadQuery<PgConnection, (U, i64)>,
+        Self: LoadQuery<TracedConnection, PageRow<U>>,
     {
-        let results = self.load::<(U, i64)>(conn)?;
-        let total = results.get(0).map(|x| x.1).unwrap_or(0);
-        let records = results.into_iter().map(|x| x.0).collect();
-        Ok((records, total))
+        let shape = PageShape::of(&self);
+        let rows = self.per_page(shape.per_page + 1).load::<PageRow<U>>(conn)?;
+        shape.page(rows)
+    }
+
+    /// Load a page for each of `partitions` with a single query, such as a page of users for each
+    /// of a number of countries.
+    ///
+    /// The query is run once per partition and should only include the rows of that partition by
+    /// comparing against [`partition_id`]. Every partition gets a page, even if it has no rows.
+    pub fn load_partitioned_pages<U>(
+        self,
+        partitions: &[i32],
+        conn: &TracedConnection,
+    ) -> QueryResult<HashMap<i32, Page<U>>>
+    where
+        Partitioned<T>: LoadQuery<TracedConnection, (i32, PageRow<U>)>,
+    {
+        let shape = PageShape::of(&self);
+        let mut partitions = partitions.to_vec();
+        partitions.sort_unstable();
+        partitions.dedup();
+
+        let query = Partitioned {
+            paginated: self.per_page(shape.per_page + 1),
+            partitions,
+        };
+        let mut rows_by_partition = query
+            .partitions
+            .iter()
+            .map(|&partition| (partition, Vec::new()))
+            .collect::<HashMap<_, _>>();
+        for (partition, row) in query.load::<(i32, PageRow<U>)>(conn)? {
+            rows_by_partition.entry(partition).or_default().push(row);
+        }
+
+        rows_by_partition
+            .into_iter()
+            .map(|(partition, rows)| Ok((partition, shape.page(rows)?)))
+            .collect()
+    }
+
+    /// Push a condition that holds for rows sorting after `values` if `order` is `Asc` and before
+    /// `values` if `order` is `Desc`, where `order` is relative to the order of the keys.
+    ///
+    /// Without `values` the condition holds for every row.
+    fn walk_keyset_condition(
+        &self,
+        values: Option<&[SortValue]>,
+        order: Order,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let values = match values {
+            Some(values) => values,
+            None => {
+                out.push_sql("TRUE");
+                return Ok(());
+            }
+        };
+
+        // Rows sorting after (a, b) are the ones where `a > $a OR (a = $a AND b > $b)`, with `>`
+        // flipped for keys sorted in descending order.
+        out.push_sql("(FALSE");
+        for i in 0..self.keys.len() {
+            out.push_sql(" OR (TRUE");
+            for (key, value) in self.keys.iter().zip(values).take(i) {
+                out.push_sql(" AND ");
+                key.walk_ast(out.reborrow())?;
+                out.push_sql(" = ");
+                value.walk_ast(out.reborrow())?;
+            }
+
+            let key = &self.keys[i];
+            out.push_sql(" AND ");
+            key.walk_ast(out.reborrow())?;
+            if key.order == order {
+                out.push_sql(" > ");
+            } else {
+                out.push_sql(" < ");
+            }
+            values[i].walk_ast(out.reborrow())?;
+            out.push_sql(")");
+        }
+        out.push_sql(")");
+        Ok(())
+    }
+
+    /// Push the keys to sort by, in reverse when paginating backwards.
+    fn walk_order(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        for (i, key) in self.keys.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            key.walk_ast(out.reborrow())?;
+            let order = match self.direction {
+                Direction::Forward => key.order,
+                Direction::Backward => key.order.reverse(),
+            };
+            match order {
+                Order::Asc => out.push_sql(" ASC"),
+                Order::Desc => out.push_sql(" DESC"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> Paginated<T>
+where
+    T: QueryFragment<Pg>,
+{
+    fn walk_total_count(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        match self.total_count {
+            TotalCount::Skip => out.push_sql("NULL::bigint"),
+            TotalCount::Exact => out.push_sql("COUNT(*) OVER ()"),
+            TotalCount::Estimate(table) => {
+                // `reltuples` is -1 for tables that have never been analyzed
+                out.push_sql(
+                    "COALESCE((SELECT CASE WHEN c.reltuples >= 0 THEN c.reltuples::bigint END \
+                     FROM pg_class c WHERE c.oid = to_regclass(",
+                );
+                out.push_bind_param::<Text, _>(&table)?;
+                out.push_sql(")), (SELECT COUNT(*) FROM (");
+                self.query.walk_ast(out.reborrow())?;
+                out.push_sql(") t))");
+            }
+        }
+        Ok(())
+    }
+
+    /// Push an expression for whether there are rows outside of `values`, i.e. rows for which the
+    /// condition made by [`Paginated::walk_keyset_condition`] doesn't hold.
+    fn walk_has_rows_outside(
+        &self,
+        values: Option<&[SortValue]>,
+        order: Order,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        if values.is_none() {
+            out.push_sql("FALSE");
+            return Ok(());
+        }
+
+        if self.total_count == TotalCount::Exact {
+            // We're looking at every row anyway to count them
+            out.push_sql("COALESCE(bool_or(NOT ");
+            self.walk_keyset_condition(values, order, out.reborrow())?;
+            out.push_sql(") OVER (), FALSE)");
+        } else {
+            // This stops at the first row found, rather than looking at every row
+            out.push_sql("EXISTS (SELECT 1 FROM (");
+            self.query.walk_ast(out.reborrow())?;
+            out.push_sql(") t WHERE NOT ");
+            self.walk_keyset_condition(values, order, out.reborrow())?;
+            out.push_sql(")");
+        }
+        Ok(())
     }
 }
 
 impl<T: Query> Query for Paginated<T> {
-    type SqlType = (T::SqlType, BigInt);
+    type SqlType = (T::SqlType, Nullable<BigInt>, Bool, Bool, Text);
+}
+
+impl<T> QueryId for Paginated<T> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
 }
 
-impl<T> RunQueryDsl<PgConnection> for Paginated<T> {}
+impl<T> RunQueryDsl<TracedConnection> for Paginated<T> {}
 
 impl<T> QueryFragment<Pg> for Paginated<T>
 where
     T: QueryFragment<Pg>,
 {
     fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
-        out.push_sql("SELECT *, COUNT(*) OVER () FROM (");
+        let after = self.after.as_deref();
+        let before = self.before.as_deref();
+
+        // The columns computed in the inner select see every row of `query` since we only filter
+        // on the keys afterwards. The keys of each row are returned as JSON so we can make cursors
+        // from them.
+        out.push_sql("SELECT *, json_build_array(");
+        for (i, key) in self.keys.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            key.walk_ast(out.reborrow())?;
+        }
+        out.push_sql(")::text FROM (SELECT *, ");
+        self.walk_total_count(out.reborrow())?;
+        out.push_sql(", ");
+        self.walk_has_rows_outside(after, Order::Asc, out.reborrow())?;
+        out.push_sql(", ");
+        self.walk_has_rows_outside(before, Order::Desc, out.reborrow())?;
+        out.push_sql(" FROM (");
         self.query.walk_ast(out.reborrow())?;
-        out.push_sql(") t LIMIT ");
+        out.push_sql(") t) t WHERE ");
+        self.walk_keyset_condition(after, Order::Asc, out.reborrow())?;
+        out.push_sql(" AND ");
+        self.walk_keyset_condition(before, Order::Desc, out.reborrow())?;
+        out.push_sql(" ORDER BY ");
+        self.walk_order(out.reborrow())?;
+        out.push_sql(" LIMIT ");
         out.push_bind_param::<BigInt, _>(&self.per_page)?;
-        out.push_sql(" OFFSET ");
-        let offset = (self.page - 1) * self.per_page;
-        out.push_bind_param::<BigInt, _>(&offset)?;
         Ok(())
     }
 }
+
+/// The rows of a [`Paginated`] query for each of a number of partitions, see
+/// [`Paginated::load_partitioned_pages`].
+pub struct Partitioned<T> {
+    paginated: Paginated<T>,
+    partitions: Vec<i32>,
+}
+
+/// The partition a query loaded with [`Paginated::load_partitioned_pages`] is run for.
+pub fn partition_id() -> SqlLiteral<Integer> {
+    sql(r#""partition"."id""#)
+}
+
+impl<T: Query> Query for Partitioned<T> {
+    type SqlType = (Integer, <Paginated<T> as Query>::SqlType);
+}
+
+impl<T> QueryId for Partitioned<T> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T> RunQueryDsl<TracedConnection> for Partitioned<T> {}
+
+impl<T> QueryFragment<Pg> for Partitioned<T>
+where
+    T: QueryFragment<Pg>,
+{
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        // The paginated query is run once per partition, which can use the same indexes as
+        // loading a single page. The rows of each partition are sorted like a single page.
+        out.push_sql(r#"SELECT "partition"."id", t.* FROM unnest("#);
+        out.push_bind_param::<Array<Integer>, _>(&self.partitions)?;
+        out.push_sql(r#") AS "partition"("id") CROSS JOIN LATERAL ("#);
+        self.paginated.walk_ast(out.reborrow())?;
+        out.push_sql(r#") t ORDER BY "partition"."id", "#);
+        self.paginated.walk_order(out.reborrow())
+    }
+}
+
+/// Encode the sort keys of a row as an opaque cursor.
+pub fn encode_cursor(values: &[SortValue]) -> String {
+    let values = values.iter().map(SortValue::to_json).collect::<Vec<_>>();
+    base64::encode(Value::Array(values).to_string())
+}
+
+fn sort_values_from_json(values: Vec<Value>, keys: &[SortKey]) -> Option<Vec<SortValue>> {
+    if values.len() != keys.len() {
+        return None;
+    }
+
+    values
+        .into_iter()
+        .zip(keys)
+        .map(|(value, key)| SortValue::from_json(value, key.kind))
+        .collect()
+}
@@ -0,0 +1,361 @@
+//! Automatic persisted queries (APQ), following Apollo's protocol.
+//!
+//! Clients send `extensions.persistedQuery.sha256Hash` instead of the query. If the hash isn't
+//! known the request fails with `PERSISTED_QUERY_NOT_FOUND`, and the client retries with both the
+//! query and the hash, which registers the query in the store for next time.
+//!
+//! Queries can also be registered up front from a manifest. With `allowlist_only` only the queries
+//! in the manifest can be executed, so clients can't send arbitrary queries.
+
+use crate::db::DbPool;
+use diesel::prelude::*;
+use lru::LruCache;
+use rocket::{fairing::AdHoc, figment::Figment, http::Status};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where queries registered by clients are kept. The methods are blocking.
+pub trait QueryStore: Send + Sync {
+    fn get(&self, hash: &str) -> Result<Option<String>, StoreError>;
+
+    fn insert(&self, hash: &str, query: &str) -> Result<(), StoreError>;
+}
+
+/// Keeps the most recently used queries in memory. Each process has its own store.
+pub struct LruStore(Mutex<LruCache<String, String>>);
+
+/// Keeps queries in the `persisted_queries` table, so they're shared by every process.
+pub struct PostgresStore(DbPool);
+
+/// Resolves the query of requests using persisted queries. Managed by Rocket.
+pub struct PersistedQueries {
+    /// `None` if clients may not register queries.
+    store: Option<Box<dyn QueryStore>>,
+    /// Queries from the manifest, by hash.
+    manifest: HashMap<String, String>,
+    allowlist_only: bool,
+}
+
+/// The query of a request, resolved by `PersistedQueries::resolve`.
+#[derive(Debug)]
+pub struct ResolvedQuery {
+    pub query: String,
+    /// The hash to register the query under, if the client sent a query that isn't registered
+    /// along with its hash.
+    unregistered_hash: Option<String>,
+}
+
+/// `extensions.persistedQuery` of a request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PersistedQuery {
+    version: i32,
+    #[serde(rename = "sha256Hash")]
+    sha256_hash: String,
+}
+
+#[derive(Debug)]
+pub enum PersistedQueryError {
+    NotFound,
+    NotSupported,
+    NotAllowed,
+    HashMismatch,
+    UnsupportedVersion(i32),
+    MissingQuery,
+    Store(StoreError),
+}
+
+#[derive(Debug, Deserialize)]
+struct PersistedQueriesConfig {
+    #[serde(default)]
+    store: StoreKind,
+    #[serde(default = "default_cache_size")]
+    cache_size: NonZeroUsize,
+    /// A manifest in Apollo's persisted query manifest format.
+    manifest: Option<PathBuf>,
+    #[serde(default)]
+    allowlist_only: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StoreKind {
+    #[default]
+    Memory,
+    Postgres,
+    Disabled,
+}
+
+fn default_cache_size() -> NonZeroUsize {
+    NonZeroUsize::new(1000).unwrap()
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    operations: Vec<ManifestOperation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestOperation {
+    id: String,
+    body: String,
+}
+
+impl PersistedQueries {
+    /// A fairing that manages `Arc<PersistedQueries>` configured under `persisted_queries`. Must
+    /// be attached after `DbPool::fairing` since the Postgres store uses the pool.
+    pub fn fairing() -> AdHoc {
+        AdHoc::try_on_ignite("Persisted queries", |rocket| async move {
+            match PersistedQueries::from_figment(rocket.figment(), rocket.state::<DbPool>()) {
+                Ok(persisted_queries) => Ok(rocket.manage(Arc::new(persisted_queries))),
+                Err(err) => {
+                    log::error!("Invalid persisted queries config: {}", err);
+                    Err(rocket)
+                }
+            }
+        })
+    }
+
+    fn from_figment(figment: &Figment, pool: Option<&DbPool>) -> Result<Self, String> {
+        let config = figment
+            .focus("persisted_queries")
+            .extract::<PersistedQueriesConfig>()
+            .map_err(|err| err.to_string())?;
+
+        let manifest = match &config.manifest {
+            Some(path) => load_manifest(path)?,
+            None => HashMap::new(),
+        };
+        if config.allowlist_only && config.manifest.is_none() {
+            return Err("`allowlist_only` requires a `manifest`".to_string());
+        }
+
+        let store: Option<Box<dyn QueryStore>> = match config.store {
+            // Clients can't register queries when only the manifest is allowed.
+            _ if config.allowlist_only => None,
+            StoreKind::Memory => Some(Box::new(LruStore::new(config.cache_size))),
+            StoreKind::Postgres => {
+                let pool = pool.ok_or("The Postgres store requires the database pool")?;
+                Some(Box::new(PostgresStore(pool.clone())))
+            }
+            StoreKind::Disabled => None,
+        };
+
+        Ok(PersistedQueries {
+            store,
+            manifest,
+            allowlist_only: config.allowlist_only,
+        })
+    }
+
+    /// The query to execute for a request with `query` and `persisted_query`. This blocks if the
+    /// store does.
+    ///
+    /// A query sent along with its hash isn't stored yet, so queries that turn out to be invalid
+    /// don't fill up the store. Call `register` once the query is known to be valid.
+    pub fn resolve(
+        &self,
+        query: Option<String>,
+        persisted_query: Option<&PersistedQuery>,
+    ) -> Result<ResolvedQuery, PersistedQueryError> {
+        let persisted_query = match persisted_query {
+            Some(persisted_query) => persisted_query,
+            None => {
+                let query = query.ok_or(PersistedQueryError::MissingQuery)?;
+                if self.allowlist_only && !self.manifest.contains_key(&sha256_hash(&query)) {
+                    return Err(PersistedQueryError::NotAllowed);
+                }
+                return Ok(ResolvedQuery::registered(query));
+            }
+        };
+
+        if persisted_query.version != 1 {
+            return Err(PersistedQueryError::UnsupportedVersion(
+                persisted_query.version,
+            ));
+        }
+        let hash = persisted_query.sha256_hash.to_ascii_lowercase();
+
+        if let Some(query) = query {
+            if sha256_hash(&query) != hash {
+                return Err(PersistedQueryError::HashMismatch);
+            }
+            if self.manifest.contains_key(&hash) {
+                return Ok(ResolvedQuery::registered(query));
+            }
+            self.store()?;
+            return Ok(ResolvedQuery {
+                query,
+                unregistered_hash: Some(hash),
+            });
+        }
+
+        if let Some(query) = self.manifest.get(&hash) {
+            return Ok(ResolvedQuery::registered(query.clone()));
+        }
+        self.store()?
+            .get(&hash)
+            .map_err(PersistedQueryError::Store)?
+            .map(ResolvedQuery::registered)
+            .ok_or(PersistedQueryError::NotFound)
+    }
+
+    /// Store the query of `resolved` if it was sent with a hash that isn't registered yet. This
+    /// blocks if the store does.
+    pub fn register(&self, resolved: &ResolvedQuery) -> Result<(), PersistedQueryError> {
+        match &resolved.unregistered_hash {
+            Some(hash) => self
+                .store()?
+                .insert(hash, &resolved.query)
+                .map_err(PersistedQueryError::Store),
+            None => Ok(()),
+        }
+    }
+
+    fn store(&self) -> Result<&dyn QueryStore, PersistedQueryError> {
+        match &self.store {
+            Some(store) => Ok(store.as_ref()),
+            None if self.allowlist_only => Err(PersistedQueryError::NotAllowed),
+            None => Err(PersistedQueryError::NotSupported),
+        }
+    }
+}
+
+impl ResolvedQuery {
+    fn registered(query: String) -> Self {
+        ResolvedQuery {
+            query,
+            unregistered_hash: None,
+        }
+    }
+}
+
+fn load_manifest(path: &Path) -> Result<HashMap<String, String>, String> {
+    let manifest = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read `{}`: {}", path.display(), err))?;
+    let manifest = serde_json::from_str::<Manifest>(&manifest)
+        .map_err(|err| format!("Invalid manifest `{}`: {}", path.display(), err))?;
+
+    manifest
+        .operations
+        .into_iter()
+        .map(|operation| {
+            let hash = sha256_hash(&operation.body);
+            if operation.id.to_ascii_lowercase() == hash {
+                Ok((hash, operation.body))
+            } else {
+                Err(format!(
+                    "The id of operation `{}` in `{}` isn't the SHA-256 hash of its body",
+                    operation.id,
+                    path.display()
+                ))
+            }
+        })
+        .collect()
+}
+
+/// The hex encoded SHA-256 hash of `query`.
+fn sha256_hash(query: &str) -> String {
+    hex::encode(Sha256::digest(query.as_bytes()))
+}
+
+impl LruStore {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        LruStore(Mutex::new(LruCache::new(capacity)))
+    }
+}
+
+impl QueryStore for LruStore {
+    fn get(&self, hash: &str) -> Result<Option<String>, StoreError> {
+        let mut cache = self.0.lock().map_err(|err| err.to_string())?;
+        Ok(cache.get(hash).cloned())
+    }
+
+    fn insert(&self, hash: &str, query: &str) -> Result<(), StoreError> {
+        let mut cache = self.0.lock().map_err(|err| err.to_string())?;
+        cache.put(hash.to_string(), query.to_string());
+        Ok(())
+    }
+}
+
+impl QueryStore for PostgresStore {
+    fn get(&self, hash: &str) -> Result<Option<String>, StoreError> {
+        use crate::schema::persisted_queries;
+        let con = self.0.get()?;
+
+        let query = persisted_queries::table
+            .find(hash)
+            .select(persisted_queries::query)
+            .first::<String>(&con)
+            .optional()?;
+        Ok(query)
+    }
+
+    fn insert(&self, hash: &str, query: &str) -> Result<(), StoreError> {
+        use crate::schema::persisted_queries;
+        let con = self.0.get()?;
+
+        diesel::insert_into(persisted_queries::table)
+            .values((
+                persisted_queries::sha256_hash.eq(hash),
+                persisted_queries::query.eq(query),
+            ))
+            .on_conflict_do_nothing()
+            .execute(&con)?;
+        Ok(())
+    }
+}
+
+impl PersistedQueryError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PersistedQueryError::NotFound => "PERSISTED_QUERY_NOT_FOUND",
+            PersistedQueryError::NotSupported => "PERSISTED_QUERY_NOT_SUPPORTED",
+            PersistedQueryError::NotAllowed => "PERSISTED_QUERY_NOT_ALLOWED",
+            PersistedQueryError::HashMismatch
+            | PersistedQueryError::UnsupportedVersion(_)
+            | PersistedQueryError::MissingQuery => "BAD_USER_INPUT",
+            PersistedQueryError::Store(_) => "INTERNAL_SERVER_ERROR",
+        }
+    }
+
+    /// Apollo clients expect `200 OK` when the query isn't found, and only retry with the query
+    /// then.
+    pub fn status(&self) -> Status {
+        match self {
+            PersistedQueryError::NotFound => Status::Ok,
+            PersistedQueryError::Store(_) => Status::InternalServerError,
+            _ => Status::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for PersistedQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Apollo clients look for these exact messages.
+            PersistedQueryError::NotFound => write!(f, "PersistedQueryNotFound"),
+            PersistedQueryError::NotSupported => write!(f, "PersistedQueryNotSupported"),
+            PersistedQueryError::NotAllowed => {
+                write!(
+                    f,
+                    "Only queries from the persisted query manifest are allowed"
+                )
+            }
+            PersistedQueryError::HashMismatch => write!(f, "provided sha does not match query"),
+            PersistedQueryError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported persisted query version {}", version)
+            }
+            PersistedQueryError::MissingQuery => write!(f, "Must provide query string"),
+            PersistedQueryError::Store(_) => write!(f, "Internal server error"),
+        }
+    }
+}
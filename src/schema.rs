@@ -12,3 +12,10 @@ table! {
         name -> Text,
     }
 }
+
+table! {
+    persisted_queries (sha256_hash) {
+        sha256_hash -> Text,
+        query -> Text,
+    }
+}
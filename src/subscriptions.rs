@@ -0,0 +1,137 @@
+//! GraphQL subscriptions over WebSocket.
+//!
+//! A Postgres trigger publishes every change to `users` on the `user_changes` channel, so changes
+//! made by other services are seen as well. One listener per process forwards the notifications to
+//! a broadcast channel and each subscription executes its operation once per matching change.
+
+use crate::db;
+use rocket::{
+    fairing::AdHoc,
+    tokio::{
+        sync::{broadcast, mpsc},
+        task, time,
+    },
+};
+use serde::Deserialize;
+use std::{future::poll_fn, time::Duration};
+use tokio_postgres::{AsyncMessage, NoTls};
+
+mod operation;
+mod protocol;
+
+pub use protocol::{GraphQLWebSocket, Session};
+
+/// The Postgres channel the `users_notify_change` trigger publishes on.
+const CHANNEL: &str = "user_changes";
+
+/// How many changes a slow subscription may fall behind before it starts missing changes.
+const CHANNEL_CAPACITY: usize = 1024;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A change to a row in `users`, as published by the `users_notify_change` trigger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct UserChange {
+    #[serde(rename = "op")]
+    pub kind: ChangeKind,
+    pub id: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ChangeKind {
+    #[serde(rename = "INSERT")]
+    Created,
+    #[serde(rename = "UPDATE")]
+    Updated,
+    #[serde(rename = "DELETE")]
+    Deleted,
+}
+
+/// Broadcasts changes to users to every subscription in this process.
+#[derive(Clone)]
+pub struct UserChanges(broadcast::Sender<UserChange>);
+
+impl UserChanges {
+    /// A fairing that manages `UserChanges` and starts listening for changes once the server has
+    /// launched.
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_ignite("User changes", |rocket| async {
+            let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+            let changes = UserChanges(sender);
+
+            rocket.manage(changes.clone()).attach(AdHoc::on_liftoff(
+                "User change listener",
+                |rocket| {
+                    Box::pin(async move {
+                        match db::database_url(rocket.figment()) {
+                            Ok(url) => {
+                                task::spawn(listen(url, changes));
+                            }
+                            Err(err) => {
+                                log::error!("Not listening for user changes: {}", err);
+                            }
+                        }
+                    })
+                },
+            ))
+        })
+    }
+
+    pub fn publish(&self, change: UserChange) {
+        // Sending only fails if nobody is subscribed, which is fine.
+        let _ = self.0.send(change);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UserChange> {
+        self.0.subscribe()
+    }
+}
+
+/// Forward notifications to `changes` forever, reconnecting if the connection is lost. Changes
+/// made while disconnected are missed.
+async fn listen(url: String, changes: UserChanges) {
+    loop {
+        if let Err(err) = forward_notifications(&url, &changes).await {
+            log::error!("Listening for user changes failed: {}", err);
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn forward_notifications(
+    url: &str,
+    changes: &UserChanges,
+) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = tokio_postgres::connect(url, NoTls).await?;
+
+    // Notifications arrive on the connection, which also has to be polled for the client to make
+    // progress, so we poll it in a separate task.
+    let (notifications_sender, mut notifications) = mpsc::unbounded_channel();
+    let connection = task::spawn(async move {
+        while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await {
+            if let AsyncMessage::Notification(notification) = message? {
+                if notifications_sender.send(notification).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    });
+
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+
+    while let Some(notification) = notifications.recv().await {
+        match serde_json::from_str(notification.payload()) {
+            Ok(change) => changes.publish(change),
+            Err(err) => log::error!("Invalid user change `{}`: {}", notification.payload(), err),
+        }
+    }
+
+    match connection.await {
+        Ok(result) => result,
+        Err(err) => {
+            log::error!("User change listener crashed: {}", err);
+            Ok(())
+        }
+    }
+}
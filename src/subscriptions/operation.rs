@@ -0,0 +1,54 @@
+//! Routing of subscription operations.
+//!
+//! Juniper parses subscription operations but refuses to execute them, so we execute subscriptions
+//! as queries against `SubscriptionSchema`, whose query type is `Subscription`. That requires
+//! knowing which root field the client subscribed to, and rewriting the `subscription` keyword to
+//! `query`. Everything else is left to Juniper's own validation.
+
+use crate::graphql::document::Operation;
+use graphql_parser::query::Selection;
+use juniper::parser::{Lexer, Token};
+
+/// The name of the single root field of a subscription, such as `userCreated`. Fails with a
+/// message suitable for clients if the subscription selects anything else.
+pub fn root_field<'a>(operation: &Operation<'a>) -> Result<&'a str, String> {
+    match operation.selection_set.items.as_slice() {
+        [Selection::Field(field)] => Ok(&field.name),
+        [Selection::FragmentSpread(_)] | [Selection::InlineFragment(_)] => {
+            Err("Subscriptions must select their root field without fragments".into())
+        }
+        _ => Err("Subscriptions must select exactly one root field".into()),
+    }
+}
+
+/// The document in `source` with the `subscription` keyword of every operation replaced by
+/// `query`. The document must already have been parsed.
+pub fn as_query(source: &str) -> String {
+    let mut document = String::with_capacity(source.len());
+    let mut copied = 0;
+    // Operations start at the top level, either at the start of the document or after the
+    // selection set of the previous definition.
+    let mut depth = 0;
+    let mut definition_start = true;
+    for token in Lexer::new(source) {
+        let token = match token {
+            Ok(token) => token.item,
+            Err(_) => break,
+        };
+        match token {
+            Token::Name(keyword @ "subscription") if definition_start => {
+                // The keyword is a slice of `source` so its offset can be found from its pointer.
+                let offset = keyword.as_ptr() as usize - source.as_ptr() as usize;
+                document.push_str(&source[copied..offset]);
+                document.push_str("query");
+                copied = offset + keyword.len();
+            }
+            Token::CurlyOpen | Token::ParenOpen | Token::BracketOpen => depth += 1,
+            Token::CurlyClose | Token::ParenClose | Token::BracketClose => depth -= 1,
+            _ => {}
+        }
+        definition_start = depth == 0 && token == Token::CurlyClose;
+    }
+    document.push_str(&source[copied..]);
+    document
+}
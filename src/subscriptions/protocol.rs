@@ -0,0 +1,511 @@
+//! The graphql-ws protocol, also known by its subprotocol name `graphql-transport-ws`.
+//!
+//! See <https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md>. Queries and mutations
+//! sent over the socket are executed once, subscriptions once per matching user change.
+
+use super::{operation, ChangeKind, UserChange, UserChanges};
+use crate::{
+    auth::{Authentication, Authenticator, CurrentUser},
+    config::AppConfig,
+    db::DbPool,
+    graphql::{
+        complexity,
+        document::{self, OperationType},
+        Context, Mutation, Schema, Subscription, SubscriptionSchema,
+    },
+    http::Extensions,
+    metrics::metrics,
+    persisted_queries::{PersistedQueries, PersistedQueryError},
+};
+use juniper::{GraphQLType, InputValue, RootNode};
+use rocket::{
+    futures::{Sink, SinkExt, Stream, StreamExt},
+    http::{Header, Status},
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+    tokio::{
+        select,
+        sync::{broadcast::error::RecvError, mpsc},
+        task::{self, AbortHandle},
+        time,
+    },
+    Orbit, Responder, Rocket,
+};
+use rocket_ws::{
+    frame::{CloseCode, CloseFrame},
+    Channel, Message, WebSocket,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub const PROTOCOL: &str = "graphql-transport-ws";
+
+/// How long clients have to send `connection_init` after connecting.
+const CONNECTION_INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit {
+        #[serde(default)]
+        payload: Option<ConnectionInitPayload>,
+    },
+    Ping {},
+    Pong {},
+    Subscribe {
+        id: String,
+        payload: SubscribePayload,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+/// Browsers can't set headers on WebSocket requests, so clients can authenticate in
+/// `connection_init` instead, with the same value as the `Authorization` header.
+#[derive(Debug, Deserialize)]
+struct ConnectionInitPayload {
+    #[serde(rename = "Authorization", alias = "authorization")]
+    authorization: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubscribePayload {
+    /// Can be left out when using a persisted query.
+    query: Option<String>,
+    operation_name: Option<String>,
+    variables: Option<InputValue>,
+    extensions: Option<Extensions>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    ConnectionAck,
+    Pong,
+    Next { id: String, payload: Value },
+    Error { id: String, payload: Value },
+    Complete { id: String },
+}
+
+/// Why the server closed the socket. The codes are defined by the protocol.
+struct Close(u16, String);
+
+/// Everything needed to execute operations for one WebSocket connection.
+#[derive(Clone)]
+pub struct Session {
+    schema: Arc<Schema>,
+    subscription_schema: Arc<SubscriptionSchema>,
+    pool: DbPool,
+    config: AppConfig,
+    persisted_queries: Arc<PersistedQueries>,
+    changes: UserChanges,
+    authenticator: Arc<Authenticator>,
+    current_user: Option<CurrentUser>,
+    /// Whether the upgrade request was authenticated. If not, the client must authenticate in
+    /// `connection_init`.
+    authenticated: bool,
+}
+
+/// The response that upgrades a request to a graphql-ws socket.
+#[derive(Responder)]
+pub struct GraphQLWebSocket(Channel<'static>, Header<'static>);
+
+impl Session {
+    /// Get everything from Rocket's managed state. Returns `None` if anything is missing.
+    ///
+    /// The session isn't authenticated until [`Session::authenticated_as`] is called or the
+    /// client sends a token in `connection_init`.
+    pub fn from_rocket(rocket: &Rocket<Orbit>) -> Option<Session> {
+        Some(Session {
+            schema: Arc::clone(rocket.state::<Arc<Schema>>()?),
+            subscription_schema: Arc::clone(rocket.state::<Arc<SubscriptionSchema>>()?),
+            pool: rocket.state::<DbPool>()?.clone(),
+            config: rocket.state::<AppConfig>()?.clone(),
+            persisted_queries: Arc::clone(rocket.state::<Arc<PersistedQueries>>()?),
+            changes: rocket.state::<UserChanges>()?.clone(),
+            authenticator: Arc::clone(rocket.state::<Arc<Authenticator>>()?),
+            current_user: None,
+            authenticated: false,
+        })
+    }
+
+    pub fn authenticated_as(self, current_user: Option<CurrentUser>) -> Self {
+        Session {
+            current_user,
+            authenticated: true,
+            ..self
+        }
+    }
+
+    /// Accept the WebSocket and serve the protocol on it.
+    pub fn accept(self, ws: WebSocket) -> GraphQLWebSocket {
+        let channel = ws.channel(move |stream| {
+            Box::pin(async move {
+                let (outgoing, incoming) = stream.split();
+                self.serve(incoming, outgoing).await;
+                Ok(())
+            })
+        });
+
+        GraphQLWebSocket(channel, Header::new("Sec-WebSocket-Protocol", PROTOCOL))
+    }
+
+    /// Serve the protocol until the client disconnects or violates the protocol.
+    pub async fn serve<I, O, E>(self, incoming: I, outgoing: O)
+    where
+        I: Stream<Item = Result<Message, E>> + Unpin,
+        O: Sink<Message> + Unpin,
+        E: fmt::Display,
+    {
+        serve(incoming, outgoing, self).await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Session {
+    type Error = ();
+
+    /// Authenticates the upgrade request if it has an `Authorization` header, so the socket is
+    /// rejected with `401 Unauthorized` if the token is invalid. Otherwise the client is
+    /// authenticated in `connection_init`.
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Session, ()> {
+        let session = match Session::from_rocket(request.rocket()) {
+            Some(session) => session,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        };
+        if !request.headers().contains("Authorization") {
+            return Outcome::Success(session);
+        }
+
+        match request.guard::<Authentication>().await {
+            Outcome::Success(Authentication(current_user)) => {
+                Outcome::Success(session.authenticated_as(current_user))
+            }
+            Outcome::Error((status, _)) => Outcome::Error((status, ())),
+            Outcome::Forward(status) => Outcome::Forward(status),
+        }
+    }
+}
+
+async fn serve<I, O, E>(mut incoming: I, mut outgoing: O, mut session: Session)
+where
+    I: Stream<Item = Result<Message, E>> + Unpin,
+    O: Sink<Message> + Unpin,
+    E: fmt::Display,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut subscriptions = HashMap::new();
+    let mut acknowledged = false;
+    let init_timeout = time::sleep(CONNECTION_INIT_TIMEOUT);
+    rocket::tokio::pin!(init_timeout);
+
+    let close = loop {
+        let result = select! {
+            message = incoming.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    handle_message(&text, &mut session, &sender, &mut subscriptions, &mut acknowledged)
+                }
+                Some(Ok(Message::Binary(_))) => {
+                    Err(Close(4400, "Binary messages are not supported".into()))
+                }
+                Some(Ok(Message::Close(_))) | None => break None,
+                Some(Ok(_)) => Ok(None),
+                Some(Err(err)) => {
+                    log::warn!("Reading from graphql-ws socket failed: {}", err);
+                    break None;
+                }
+            },
+            Some(message) = receiver.recv() => {
+                if let ServerMessage::Complete { id } | ServerMessage::Error { id, .. } = &message {
+                    subscriptions.remove(id);
+                }
+                Ok(Some(message))
+            }
+            _ = &mut init_timeout, if !acknowledged => {
+                Err(Close(4408, "Connection initialisation timeout".into()))
+            }
+        };
+
+        let message = match result {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(close) => break Some(close),
+        };
+        let text = serde_json::to_string(&message).expect("server messages are serializable");
+        if outgoing.send(Message::Text(text)).await.is_err() {
+            break None;
+        }
+    };
+
+    for (_, subscription) in subscriptions {
+        subscription.abort();
+    }
+
+    if let Some(Close(code, reason)) = close {
+        let frame = CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.into(),
+        };
+        let _ = outgoing.send(Message::Close(Some(frame))).await;
+    }
+}
+
+/// Handle a message from the client, returning the reply, if any.
+fn handle_message(
+    text: &str,
+    session: &mut Session,
+    sender: &mpsc::UnboundedSender<ServerMessage>,
+    subscriptions: &mut HashMap<String, AbortHandle>,
+    acknowledged: &mut bool,
+) -> Result<Option<ServerMessage>, Close> {
+    let message = serde_json::from_str::<ClientMessage>(text)
+        .map_err(|err| Close(4400, format!("Invalid message received: {}", err)))?;
+
+    match message {
+        ClientMessage::ConnectionInit { payload } => {
+            if *acknowledged {
+                return Err(Close(4429, "Too many initialisation requests".into()));
+            }
+            authenticate(session, payload)?;
+            *acknowledged = true;
+            Ok(Some(ServerMessage::ConnectionAck))
+        }
+        ClientMessage::Ping { .. } => Ok(Some(ServerMessage::Pong)),
+        ClientMessage::Pong { .. } => Ok(None),
+        ClientMessage::Subscribe { id, payload } => {
+            if !*acknowledged {
+                return Err(Close(4401, "Unauthorized".into()));
+            }
+            if subscriptions.contains_key(&id) {
+                return Err(Close(4409, format!("Subscriber for {} already exists", id)));
+            }
+            match start(id.clone(), payload, session, sender.clone()) {
+                Ok(subscription) => {
+                    subscriptions.insert(id, subscription);
+                    Ok(None)
+                }
+                Err(errors) => {
+                    metrics().record_rejection();
+                    metrics().record_errors(&json!({ "errors": errors }));
+                    Ok(Some(ServerMessage::Error {
+                        id,
+                        payload: errors,
+                    }))
+                }
+            }
+        }
+        ClientMessage::Complete { id } => {
+            if let Some(subscription) = subscriptions.remove(&id) {
+                subscription.abort();
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Authenticate the session with the token in `connection_init`, if any. Fails if the token is
+/// invalid, or if there's neither a token nor an authenticated upgrade request and anonymous
+/// access isn't allowed.
+fn authenticate(
+    session: &mut Session,
+    payload: Option<ConnectionInitPayload>,
+) -> Result<(), Close> {
+    let authorization = payload.and_then(|payload| payload.authorization);
+    if authorization.is_none() && session.authenticated {
+        return Ok(());
+    }
+
+    match session.authenticator.authenticate(authorization.as_deref()) {
+        Ok(current_user) => {
+            session.current_user = current_user;
+            session.authenticated = true;
+            Ok(())
+        }
+        Err(err) => {
+            log::info!("Rejected graphql-ws connection: {}", err);
+            Err(Close(4403, "Forbidden".into()))
+        }
+    }
+}
+
+/// Start executing an operation, sending results to `sender`. Fails with the errors to send to
+/// the client if the operation can't be executed at all.
+///
+/// Like over HTTP, the query is resolved from the persisted queries and checked against the
+/// complexity limits first.
+fn start(
+    id: String,
+    payload: SubscribePayload,
+    session: &Session,
+    sender: mpsc::UnboundedSender<ServerMessage>,
+) -> Result<AbortHandle, Value> {
+    let persisted_query = payload
+        .extensions
+        .as_ref()
+        .and_then(|extensions| extensions.persisted_query.as_ref());
+    // The store can block. Subscriptions must have started before the next message is handled,
+    // so rather than resolving on another thread we let Tokio move other tasks off this one.
+    let persisted_query_error = |err: PersistedQueryError| {
+        if let PersistedQueryError::Store(store_err) = &err {
+            log::error!("Persisted query store failed: {}", store_err);
+        }
+        json!([{ "message": err.to_string(), "extensions": { "code": err.code() } }])
+    };
+    let query = payload.query;
+    let resolved =
+        task::block_in_place(|| session.persisted_queries.resolve(query, persisted_query))
+            .map_err(persisted_query_error)?;
+
+    let document_error = |err: document::DocumentError| {
+        let extensions = json!({ "code": err.code() });
+        json!([{ "message": err.to_string(), "extensions": extensions }])
+    };
+    let document = document::parse(&resolved.query).map_err(document_error)?;
+    let operation = document::find_operation(&document, payload.operation_name.as_deref())
+        .map_err(document_error)?;
+    complexity::check(
+        &document,
+        &operation,
+        payload.variables.as_ref(),
+        &session.config,
+    )
+    .map_err(|err| json!([{ "message": err.to_string(), "extensions": err.extensions() }]))?;
+    task::block_in_place(|| session.persisted_queries.register(&resolved))
+        .map_err(persisted_query_error)?;
+    let query = resolved.query;
+    let session = session.clone();
+
+    if operation.operation_type != OperationType::Subscription {
+        let request =
+            juniper::http::GraphQLRequest::new(query, payload.operation_name, payload.variables);
+        let handle = task::spawn(async move {
+            let schema = Arc::clone(&session.schema);
+            let message = execute(schema, request, &session, None, id.clone()).await;
+            if let ServerMessage::Next { .. } = message {
+                let _ = sender.send(message);
+                let _ = sender.send(ServerMessage::Complete { id });
+            } else {
+                let _ = sender.send(message);
+            }
+        });
+        return Ok(handle.abort_handle());
+    }
+
+    let root_field =
+        operation::root_field(&operation).map_err(|message| json!([{ "message": message }]))?;
+    Subscription::authorize(root_field, session.current_user.as_ref()).map_err(
+        |err| json!([{ "message": err.to_string(), "extensions": { "code": err.code() } }]),
+    )?;
+    let kind = match root_field {
+        "userCreated" => ChangeKind::Created,
+        "userUpdated" => ChangeKind::Updated,
+        "userDeleted" => ChangeKind::Deleted,
+        field => {
+            let message = format!("Unknown field \"{}\" on type \"Subscription\"", field);
+            return Err(json!([{ "message": message }]));
+        }
+    };
+    let request = juniper::http::GraphQLRequest::new(
+        operation::as_query(&query),
+        payload.operation_name,
+        payload.variables,
+    );
+
+    // Subscribe before returning so no changes are missed once the client is told about them.
+    let mut changes = session.changes.subscribe();
+    let handle = task::spawn(async move {
+        loop {
+            let change = match changes.recv().await {
+                Ok(change) if change.kind == kind => change,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Subscription {} missed {} user changes", id, skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    let _ = sender.send(ServerMessage::Complete { id });
+                    return;
+                }
+            };
+
+            let schema = Arc::clone(&session.subscription_schema);
+            let message = execute(schema, request.clone(), &session, Some(change), id.clone());
+            let message = message.await;
+            let failed = matches!(message, ServerMessage::Error { .. });
+            if sender.send(message).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    Ok(handle.abort_handle())
+}
+
+/// Execute `request` on a blocking thread. Requests that can't be executed, for example because
+/// they fail validation, result in an `error` message.
+async fn execute<Q>(
+    schema: Arc<RootNode<'static, Q, Mutation>>,
+    request: juniper::http::GraphQLRequest,
+    session: &Session,
+    change: Option<UserChange>,
+    id: String,
+) -> ServerMessage
+where
+    Q: GraphQLType<Context = Context, TypeInfo = ()> + Send + Sync + 'static,
+{
+    let pool = session.pool.clone();
+    let config = session.config.clone();
+    let current_user = session.current_user.clone();
+
+    let result = task::spawn_blocking(move || {
+        let db_con = pool.get().map_err(|err| {
+            log::error!("Failed to get database connection: {}", err);
+            Status::ServiceUnavailable
+        })?;
+        let context = Context::new(db_con, config, current_user).with_user_change(change);
+
+        let start = Instant::now();
+        let response = request.execute(&schema, &context);
+        let duration = start.elapsed();
+        let payload = serde_json::to_value(&response).map_err(|err| {
+            log::error!("Failed to serialize GraphQL response: {}", err);
+            Status::InternalServerError
+        })?;
+        // Like over HTTP, operations that fail validation are counted as rejected.
+        if payload.get("data").is_some() {
+            metrics().record_execution(request.operation_name(), duration);
+        } else {
+            metrics().record_rejection();
+        }
+        metrics().record_errors(&payload);
+        Ok::<_, Status>((response.is_ok(), payload))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((true, payload))) => ServerMessage::Next { id, payload },
+        Ok(Ok((false, payload))) => ServerMessage::Error {
+            id,
+            payload: payload["errors"].clone(),
+        },
+        Ok(Err(status)) => ServerMessage::Error {
+            id,
+            payload: json!([{ "message": status.reason_lossy() }]),
+        },
+        Err(err) => {
+            log::error!("Executing GraphQL request failed: {}", err);
+            ServerMessage::Error {
+                id,
+                payload: json!([{ "message": "Internal server error" }]),
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Structured logs and tracing spans.
+//!
+//! Events and spans are written to stdout as JSON, one object per line. Spans are logged when
+//! they close, with how long they took as `time.busy` and `time.idle`. Records from the `log`
+//! crate, which Rocket uses, are logged the same way.
+//!
+//! If `telemetry.otlp_endpoint` is set the spans are also exported over OTLP/HTTP, for example to
+//! a local OpenTelemetry collector.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use rocket::figment::Figment;
+use serde::Deserialize;
+use tracing_subscriber::{
+    fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+};
+
+#[derive(Debug, Deserialize)]
+struct TelemetryConfig {
+    /// Which spans and events are recorded, in the syntax of `RUST_LOG`.
+    #[serde(default = "default_filter")]
+    filter: String,
+    /// Where to export spans to, such as `http://localhost:4318/v1/traces`.
+    otlp_endpoint: Option<String>,
+}
+
+fn default_filter() -> String {
+    "info".to_string()
+}
+
+/// Exports the remaining spans when dropped.
+pub struct Telemetry(Option<SdkTracerProvider>);
+
+/// Install the global subscriber configured under `telemetry`. This has to happen before Rocket
+/// starts logging, and outside of the async runtime since the OTLP exporter blocks.
+pub fn init(figment: &Figment) -> Result<Telemetry, String> {
+    let config = figment
+        .focus("telemetry")
+        .extract::<TelemetryConfig>()
+        .map_err(|err| err.to_string())?;
+
+    let filter = EnvFilter::try_new(&config.filter)
+        .map_err(|err| format!("Invalid filter `{}`: {}", config.filter, err))?;
+    let json = tracing_subscriber::fmt::layer()
+        .json()
+        .with_span_list(true)
+        .with_span_events(FmtSpan::CLOSE);
+
+    let provider = match config.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()
+                .map_err(|err| format!("Failed to create OTLP exporter: {}", err))?;
+            let resource = Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build();
+            let provider = SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(resource)
+                .build();
+            Some(provider)
+        }
+        None => None,
+    };
+    let otlp = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(otlp)
+        .try_init()
+        .map_err(|err| err.to_string())?;
+
+    Ok(Telemetry(provider))
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = &self.0 {
+            if let Err(err) = provider.shutdown() {
+                log::error!("Failed to export spans: {}", err);
+            }
+        }
+    }
+}
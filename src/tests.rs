@@ -1,13 +1,41 @@
-use crate::{models::*, DbCon};
+use crate::{
+    auth::CurrentUser,
+    db::{DbCon, DbPool},
+    graphql::{
+        global_id::{self, NodeType},
+        Role,
+    },
+    metrics::OperationLabels,
+    models::{pagination::encode_cursor, *},
+    subscriptions::{ChangeKind, Session, UserChange, UserChanges},
+};
 use assert_json_diff::{assert_json_eq, assert_json_include};
 use diesel::prelude::*;
 use diesel_factories::{Association, Factory};
 use juniper::ID;
 use rocket::{
-    http::{ContentType, Status},
-    local::Client,
+    figment::Figment,
+    futures::{channel::mpsc, StreamExt},
+    http::{ContentType, Header, Status},
+    local::blocking::Client,
 };
+use rocket_ws::Message;
 use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Mutex, Once},
+    time::Duration,
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{self, SubscriberExt},
+    registry::LookupSpan,
+    Layer,
+};
 
 #[test]
 fn test_nothing_to_begin_with() {
@@ -56,7 +84,7 @@ fn test_loading_user() {
             "data": {
                 "users": [
                     {
-                        "id": ID::new(user.id.to_string()),
+                        "id": user_id(&user),
                         "name": user.name,
                     },
                 ],
@@ -66,6 +94,43 @@ fn test_loading_user() {
     );
 }
 
+#[test]
+fn test_requests_over_get_and_raw_graphql_bodies() {
+    let client = setup();
+
+    let user = {
+        let con = get_db_con(&client);
+        UserFactory::default().insert(&con)
+    };
+    let expected = json!({
+        "data": {
+            "user": { "name": user.name },
+        },
+    });
+
+    let query = "query User($id: ID!) { user(id: $id) { name } }";
+    let variables = json!({ "id": user_id(&user) }).to_string();
+    let uri = format!(
+        "/graphql?query={}&variables={}",
+        rocket::http::RawStr::new(query).percent_encode(),
+        rocket::http::RawStr::new(&variables).percent_encode(),
+    );
+    let response = client.get(uri).dispatch();
+    assert_eq!(Status::Ok, response.status());
+    let json = serde_json::from_str::<Value>(&response.into_string().unwrap()).unwrap();
+    assert_json_eq!(expected.clone(), json);
+
+    let query = format!(r#"{{ user(id: "{}") {{ name }} }}"#, &*user_id(&user));
+    let response = client
+        .post("/graphql")
+        .header(ContentType::new("application", "graphql"))
+        .body(query)
+        .dispatch();
+    assert_eq!(Status::Ok, response.status());
+    let json = serde_json::from_str::<Value>(&response.into_string().unwrap()).unwrap();
+    assert_json_eq!(expected, json);
+}
+
 #[test]
 fn test_loading_users_with_countries() {
     let client = setup();
@@ -98,10 +163,10 @@ fn test_loading_users_with_countries() {
             "data": {
                 "users": [
                     {
-                        "id": ID::new(user.id.to_string()),
+                        "id": user_id(&user),
                         "name": user.name,
                         "country": {
-                            "id": ID::new(country.id.to_string()),
+                            "id": country_id(&country),
                             "name": country.name,
                         },
                     },
@@ -113,255 +178,3176 @@ fn test_loading_users_with_countries() {
 }
 
 #[test]
-fn test_paginating_users() {
+fn test_loading_users_of_countries() {
     let client = setup();
 
     let (user_1, user_2, user_3) = {
         let con = get_db_con(&client);
-        let user_1 = UserFactory::default().name("1").insert(&con);
-        let user_2 = UserFactory::default().name("2").insert(&con);
-        let user_3 = UserFactory::default().name("3").insert(&con);
+        let copenhagen = CountryFactory::default().name("Copenhagen").insert(&con);
+        let berlin = CountryFactory::default().name("Berlin").insert(&con);
+        CountryFactory::default().name("Oslo").insert(&con);
+        let user_1 = UserFactory::default()
+            .name("1")
+            .country(&copenhagen)
+            .insert(&con);
+        let user_2 = UserFactory::default()
+            .name("2")
+            .country(&berlin)
+            .insert(&con);
+        let user_3 = UserFactory::default()
+            .name("3")
+            .country(&copenhagen)
+            .insert(&con);
         (user_1, user_2, user_3)
     };
 
     let query = r#"
         {
-            userConnections(first: 1) {
+            countryConnections {
                 edges {
-                    cursor
                     node {
-                        id
                         name
+                        users {
+                            name
+                            country {
+                                name
+                            }
+                        }
                     }
                 }
-                pageInfo {
-                    startCursor
-                    endCursor
-                    hasNextPage
-                }
-                totalCount
             }
         }
         "#;
     let (json, status) = make_request(&client, query, None);
 
     assert_eq!(Status::Ok, status);
-    assert_json_include!(
-        expected: json!({
+    assert_json_eq!(
+        json!({
             "data": {
-                "userConnections": {
+                "countryConnections": {
                     "edges": [
                         {
-                            "cursor": "2",
-                            "node": { "name": user_1.name },
-                        }
+                            "node": {
+                                "name": "Copenhagen",
+                                "users": [
+                                    { "name": user_1.name, "country": { "name": "Copenhagen" } },
+                                    { "name": user_3.name, "country": { "name": "Copenhagen" } },
+                                ],
+                            },
+                        },
+                        {
+                            "node": {
+                                "name": "Berlin",
+                                "users": [
+                                    { "name": user_2.name, "country": { "name": "Berlin" } },
+                                ],
+                            },
+                        },
+                        {
+                            "node": {
+                                "name": "Oslo",
+                                "users": [],
+                            },
+                        },
                     ],
-                    "pageInfo": {
-                        "startCursor": "2",
-                        "endCursor": "2",
-                        "hasNextPage": true,
-                    },
-                    "totalCount": 3,
-                }
+                },
             },
         }),
-        actual: json.clone(),
+        json,
     );
-    let cursor = json["data"]["userConnections"]["pageInfo"]["endCursor"]
-        .as_str()
-        .unwrap();
+}
+
+#[test]
+fn test_paginating_users_of_country() {
+    let client = setup();
+
+    let user_1 = {
+        let con = get_db_con(&client);
+        let copenhagen = CountryFactory::default().name("Copenhagen").insert(&con);
+        let berlin = CountryFactory::default().name("Berlin").insert(&con);
+        let user_1 = UserFactory::default()
+            .name("1")
+            .country(&copenhagen)
+            .insert(&con);
+        UserFactory::default()
+            .name("2")
+            .country(&berlin)
+            .insert(&con);
+        UserFactory::default()
+            .name("3")
+            .country(&copenhagen)
+            .insert(&con);
+        user_1
+    };
 
     let query = r#"
-        query Test($after: Cursor!) {
-            userConnections(first: 1, after: $after) {
+        {
+            countryConnections(first: 1) {
                 edges {
-                    cursor
                     node {
-                        id
-                        name
+                        userConnections(first: 1) {
+                            edges {
+                                node {
+                                    name
+                                }
+                            }
+                            pageInfo {
+                                endCursor
+                                hasNextPage
+                            }
+                            totalCount
+                        }
                     }
                 }
-                pageInfo {
-                    startCursor
-                    endCursor
-                    hasNextPage
-                }
-                totalCount
             }
         }
         "#;
-    let vars = json!({ "after": cursor });
-    let (json, status) = make_request(&client, query, Some(vars));
+    let (json, status) = make_request(&client, query, None);
 
     assert_eq!(Status::Ok, status);
-    assert_json_include!(
-        expected: json!({
+    assert_json_eq!(
+        json!({
             "data": {
-                "userConnections": {
+                "countryConnections": {
                     "edges": [
                         {
-                            "cursor": "3",
-                            "node": { "name": user_2.name },
-                        }
+                            "node": {
+                                "userConnections": {
+                                    "edges": [{ "node": { "name": user_1.name } }],
+                                    "pageInfo": {
+                                        "endCursor": encode_cursor(&[user_1.id.into()]),
+                                        "hasNextPage": true,
+                                    },
+                                    "totalCount": 2,
+                                },
+                            },
+                        },
                     ],
-                    "pageInfo": {
-                        "startCursor": "3",
-                        "endCursor": "3",
-                        "hasNextPage": true,
-                    },
-                    "totalCount": 3,
-                }
+                },
             },
         }),
-        actual: json.clone(),
+        json,
     );
-    let cursor = json["data"]["userConnections"]["pageInfo"]["endCursor"]
-        .as_str()
-        .unwrap();
+}
 
-    let vars = json!({ "after": cursor });
-    let (json, status) = make_request(&client, query, Some(vars));
+#[test]
+fn test_paginating_users_of_countries_in_one_query() {
+    capture_spans();
+    let client = setup();
+
+    let (user_1, user_2, user_3) = {
+        let con = get_db_con(&client);
+        let copenhagen = CountryFactory::default().name("Copenhagen").insert(&con);
+        let berlin = CountryFactory::default().name("Berlin").insert(&con);
+        CountryFactory::default().name("Oslo").insert(&con);
+        let user_1 = UserFactory::default()
+            .name("1")
+            .country(&copenhagen)
+            .insert(&con);
+        let user_2 = UserFactory::default()
+            .name("2")
+            .country(&berlin)
+            .insert(&con);
+        let user_3 = UserFactory::default()
+            .name("3")
+            .country(&copenhagen)
+            .insert(&con);
+        (user_1, user_2, user_3)
+    };
+
+    // `last` is selected with an alias, so it can't use the eager loaded connections. `byName`
+    // asks for the same page as `userConnections`, so it can.
+    let body = json!({
+        "query": r#"
+            query UsersOfCountries {
+                countries {
+                    name
+                    userConnections(first: 1, orderBy: [{ field: NAME }]) {
+                        edges { node { name country { name } } }
+                        pageInfo { hasNextPage }
+                        totalCount
+                    }
+                    byName: userConnections(first: 1, orderBy: [{ field: NAME, direction: ASC }]) {
+                        edges { node { name } }
+                    }
+                    last: userConnections(last: 1) {
+                        edges { node { name } }
+                    }
+                }
+            }
+        "#,
+        "operationName": "UsersOfCountries",
+    });
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, Some("admin")));
+    let (json, status) = send_request(&client, Some(authorization), body);
 
     assert_eq!(Status::Ok, status);
-    assert_json_include!(
-        expected: json!({
+    assert_json_eq!(
+        json!({
             "data": {
-                "userConnections": {
-                    "edges": [
-                        {
-                            "cursor": "4",
-                            "node": { "name": user_3.name },
-                        }
-                    ],
-                    "pageInfo": {
-                        "startCursor": "4",
-                        "endCursor": "4",
-                        "hasNextPage": false,
+                "countries": [
+                    {
+                        "name": "Copenhagen",
+                        "userConnections": {
+                            "edges": [
+                                { "node": { "name": user_1.name, "country": { "name": "Copenhagen" } } },
+                            ],
+                            "pageInfo": { "hasNextPage": true },
+                            "totalCount": 2,
+                        },
+                        "byName": { "edges": [{ "node": { "name": user_1.name } }] },
+                        "last": { "edges": [{ "node": { "name": user_3.name } }] },
                     },
-                    "totalCount": 3,
-                }
+                    {
+                        "name": "Berlin",
+                        "userConnections": {
+                            "edges": [
+                                { "node": { "name": user_2.name, "country": { "name": "Berlin" } } },
+                            ],
+                            "pageInfo": { "hasNextPage": false },
+                            "totalCount": 1,
+                        },
+                        "byName": { "edges": [{ "node": { "name": user_2.name } }] },
+                        "last": { "edges": [{ "node": { "name": user_2.name } }] },
+                    },
+                    {
+                        "name": "Oslo",
+                        "userConnections": {
+                            "edges": [],
+                            "pageInfo": { "hasNextPage": false },
+                            "totalCount": 0,
+                        },
+                        "byName": { "edges": [] },
+                        "last": { "edges": [] },
+                    },
+                ],
             },
         }),
-        actual: json.clone(),
+        json,
     );
-    let cursor = json["data"]["userConnections"]["pageInfo"]["endCursor"]
-        .as_str()
-        .unwrap();
 
-    let vars = json!({ "after": cursor });
-    let (json, status) = make_request(&client, query, Some(vars));
-    assert_eq!(Status::Ok, status);
-    let edges = json["data"]["userConnections"]["edges"].as_array().unwrap();
-    assert_eq!(edges.len(), 0);
+    let queries = CLOSED_SPANS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|span| span.operation.as_deref() == Some("UsersOfCountries"))
+        .filter(|span| span.name == "db.query")
+        .count();
+    // The countries, a page of users for every country, and a page for each aliased connection
+    assert_eq!(2 + 3, queries);
 }
 
 #[test]
-fn test_paginating_users_with_no_users() {
+fn test_loading_single_records() {
     let client = setup();
 
+    let (user, country) = {
+        let con = get_db_con(&client);
+        let country = CountryFactory::default().insert(&con);
+        let user = UserFactory::default().country(&country).insert(&con);
+        (user, country)
+    };
+
     let query = r#"
-        {
-            userConnections {
-                edges {
-                    cursor
-                    node {
-                        id
-                    }
+        query Test($userId: ID!, $countryId: ID!, $missingUserId: ID!) {
+            user(id: $userId) {
+                name
+                country {
+                    name
                 }
-                pageInfo {
-                    startCursor
-                    endCursor
-                    hasNextPage
+            }
+            country(id: $countryId) {
+                name
+                users {
+                    name
                 }
             }
+            countries {
+                name
+            }
+            missingUser: user(id: $missingUserId) {
+                name
+            }
         }
         "#;
+    let vars = json!({
+        "userId": user_id(&user),
+        "countryId": country_id(&country),
+        "missingUserId": global_id::encode(NodeType::User, 0),
+    });
+    let (json, status) = make_request(&client, query, Some(vars));
 
-    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "user": {
+                    "name": user.name,
+                    "country": { "name": country.name },
+                },
+                "country": {
+                    "name": country.name,
+                    "users": [{ "name": user.name }],
+                },
+                "countries": [{ "name": country.name }],
+                "missingUser": null,
+            },
+        }),
+        json,
+    );
+
+    let (json, status) = make_request(&client, r#"{ country(id: "abc") { name } }"#, None);
 
     assert_eq!(Status::Ok, status);
     assert_json_include!(
         expected: json!({
-            "data": {
-                "userConnections": {
-                    "edges": [],
-                    "pageInfo": {
-                        "startCursor": null,
-                        "endCursor": null,
-                        "hasNextPage": false,
-                    }
-                }
-            },
+            "data": { "country": null },
+            "errors": [{ "extensions": { "code": "INVALID_ID" } }],
         }),
-        actual: json.clone(),
+        actual: json,
     );
-    let edges = json["data"]["userConnections"]["edges"].as_array().unwrap();
-    assert_eq!(edges.len(), 0);
 }
 
-#[derive(Clone, Factory)]
-#[factory(
-    model = User,
-    table = crate::schema::users,
-    connection = PgConnection
-)]
-struct UserFactory<'a> {
-    pub name: String,
-    pub country: Association<'a, Country, CountryFactory>,
-}
+#[test]
+fn test_loading_nodes_by_global_id() {
+    let client = setup();
 
-impl Default for UserFactory<'_> {
-    fn default() -> Self {
-        Self {
-            name: "Bob".to_string(),
-            country: Association::default(),
-        }
-    }
-}
+    let (user, country) = {
+        let con = get_db_con(&client);
+        let country = CountryFactory::default().insert(&con);
+        let user = UserFactory::default().country(&country).insert(&con);
+        (user, country)
+    };
 
-#[derive(Clone, Factory)]
-#[factory(
-    model = Country,
-    table = crate::schema::countries,
-    connection = PgConnection
-)]
-struct CountryFactory {
-    pub name: String,
-}
+    assert_ne!(user_id(&user), country_id(&country));
 
-impl Default for CountryFactory {
-    fn default() -> Self {
-        Self {
-            name: "Copenhagen".to_string(),
+    let query = r#"
+        query Test($userId: ID!, $countryId: ID!, $ids: [ID!]!) {
+            user: node(id: $userId) {
+                id
+                ... on User {
+                    name
+                    country {
+                        name
+                    }
+                }
+            }
+            country: node(id: $countryId) {
+                id
+                ... on Country {
+                    name
+                }
+            }
+            nodes(ids: $ids) {
+                __typename
+                id
+            }
         }
-    }
-}
-
-fn setup() -> Client {
-    let rocket = crate::rocket();
-    let con = DbCon::get_one(&rocket).expect("get db con");
-    con.begin_test_transaction()
-        .expect("begin test transaction");
-    drop(con);
+        "#;
+    let vars = json!({
+        "userId": user_id(&user),
+        "countryId": country_id(&country),
+        "ids": [
+            country_id(&country),
+            global_id::encode(NodeType::User, 0),
+            user_id(&user),
+        ],
+    });
+    let (json, status) = make_request(&client, query, Some(vars));
 
-    Client::new(rocket).expect("create test client")
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "user": {
+                    "id": user_id(&user),
+                    "name": user.name,
+                    "country": { "name": country.name },
+                },
+                "country": {
+                    "id": country_id(&country),
+                    "name": country.name,
+                },
+                "nodes": [
+                    { "__typename": "Country", "id": country_id(&country) },
+                    null,
+                    { "__typename": "User", "id": user_id(&user) },
+                ],
+            },
+        }),
+        json,
+    );
+
+    // A user ID cannot be used where a country ID is expected
+    let query = "query Test($id: ID!) { country(id: $id) { name } }";
+    let vars = json!({ "id": user_id(&user) });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": { "country": null },
+            "errors": [{ "extensions": { "code": "INVALID_ID" } }],
+        }),
+        actual: json,
+    );
+}
+
+#[test]
+fn test_filtering_users() {
+    let client = setup();
+
+    let (denmark, sweden) = {
+        let con = get_db_con(&client);
+        let denmark = CountryFactory::default().name("Denmark").insert(&con);
+        let sweden = CountryFactory::default().name("Sweden").insert(&con);
+        UserFactory::default()
+            .name("Alice")
+            .country(&denmark)
+            .insert(&con);
+        UserFactory::default()
+            .name("alfred")
+            .country(&sweden)
+            .insert(&con);
+        UserFactory::default()
+            .name("Bob")
+            .country(&sweden)
+            .insert(&con);
+        UserFactory::default()
+            .name("100%")
+            .country(&denmark)
+            .insert(&con);
+        (denmark, sweden)
+    };
+
+    let query = r#"
+        query Test($filter: UserFilter) {
+            users(filter: $filter) {
+                name
+            }
+        }
+        "#;
+    let user_names = |filter: Value| {
+        let (json, status) = make_request(&client, query, Some(json!({ "filter": filter })));
+        assert_eq!(Status::Ok, status);
+        let mut names = json["data"]["users"]
+            .as_array()
+            .unwrap_or_else(|| panic!("unexpected response {}", json))
+            .iter()
+            .map(|user| user["name"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        vec!["100%", "Alice", "Bob", "alfred"],
+        user_names(Value::Null)
+    );
+    assert_eq!(
+        vec!["Alice"],
+        user_names(json!({ "name": { "equals": "Alice" } }))
+    );
+    assert_eq!(
+        vec!["Alice"],
+        user_names(json!({ "name": { "equals": "ALICE", "caseInsensitive": true } }))
+    );
+    assert_eq!(
+        Vec::<String>::new(),
+        user_names(json!({ "name": { "equals": "ALICE" } }))
+    );
+    assert_eq!(
+        vec!["Alice", "alfred"],
+        user_names(json!({ "name": { "startsWith": "al", "caseInsensitive": true } }))
+    );
+    assert_eq!(
+        vec!["100%"],
+        user_names(json!({ "name": { "contains": "0%" } }))
+    );
+    assert_eq!(
+        vec!["Bob", "alfred"],
+        user_names(json!({ "countryIdIn": [country_id(&sweden)] }))
+    );
+    assert_eq!(
+        vec!["100%", "Alice", "Bob", "alfred"],
+        user_names(json!({ "countryIdIn": [country_id(&sweden), country_id(&denmark)] }))
+    );
+    assert_eq!(
+        vec!["Alice"],
+        user_names(json!({
+            "name": { "startsWith": "a", "caseInsensitive": true },
+            "countryName": { "equals": "denmark", "caseInsensitive": true },
+        }))
+    );
+}
+
+#[test]
+fn test_filtered_user_connections_count_matching_users() {
+    let client = setup();
+
+    let (user_1, user_2) = {
+        let con = get_db_con(&client);
+        let user_1 = UserFactory::default().name("Alice").insert(&con);
+        UserFactory::default().name("Bob").insert(&con);
+        let user_2 = UserFactory::default().name("Alfred").insert(&con);
+        (user_1, user_2)
+    };
+
+    let query = r#"
+        query Test($after: Cursor) {
+            userConnections(first: 1, after: $after, filter: { name: { startsWith: "Al" } }) {
+                edges {
+                    node {
+                        name
+                    }
+                }
+                pageInfo {
+                    hasNextPage
+                }
+                totalCount
+            }
+        }
+        "#;
+
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "userConnections": {
+                    "edges": [{ "node": { "name": user_1.name } }],
+                    "pageInfo": { "hasNextPage": true },
+                    "totalCount": 2,
+                },
+            },
+        }),
+        json,
+    );
+
+    let vars = json!({ "after": encode_cursor(&[user_1.id.into()]) });
+    let (json, status) = make_request(&client, query, Some(vars));
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "userConnections": {
+                    "edges": [{ "node": { "name": user_2.name } }],
+                    "pageInfo": { "hasNextPage": false },
+                    "totalCount": 2,
+                },
+            },
+        }),
+        json,
+    );
+}
+
+#[test]
+fn test_ordering_user_connections() {
+    let client = setup();
+
+    {
+        let con = get_db_con(&client);
+        let denmark = CountryFactory::default().name("Denmark").insert(&con);
+        let sweden = CountryFactory::default().name("Sweden").insert(&con);
+        UserFactory::default()
+            .name("Bob")
+            .country(&sweden)
+            .insert(&con);
+        UserFactory::default()
+            .name("Alice")
+            .country(&sweden)
+            .insert(&con);
+        UserFactory::default()
+            .name("Carol")
+            .country(&denmark)
+            .insert(&con);
+        UserFactory::default()
+            .name("Bob")
+            .country(&denmark)
+            .insert(&con);
+    }
+
+    let query = r#"
+        query Test($orderBy: [UserOrder!], $after: Cursor) {
+            userConnections(first: 2, after: $after, orderBy: $orderBy) {
+                edges {
+                    node {
+                        name
+                        country {
+                            name
+                        }
+                    }
+                }
+                pageInfo {
+                    endCursor
+                    hasNextPage
+                }
+            }
+        }
+        "#;
+    // Load every page and return "<name> (<country>)" for each user
+    let load_all = |order_by: Value| {
+        let mut users = Vec::new();
+        let mut after = Value::Null;
+        loop {
+            let vars = json!({ "orderBy": order_by, "after": after });
+            let (json, status) = make_request(&client, query, Some(vars));
+            assert_eq!(Status::Ok, status);
+
+            let connection = &json["data"]["userConnections"];
+            let edges = connection["edges"]
+                .as_array()
+                .unwrap_or_else(|| panic!("unexpected response {}", json));
+            for edge in edges {
+                users.push(format!(
+                    "{} ({})",
+                    edge["node"]["name"].as_str().unwrap(),
+                    edge["node"]["country"]["name"].as_str().unwrap(),
+                ));
+            }
+
+            if connection["pageInfo"]["hasNextPage"] == json!(false) {
+                return users;
+            }
+            after = connection["pageInfo"]["endCursor"].clone();
+        }
+    };
+
+    assert_eq!(
+        vec![
+            "Carol (Denmark)",
+            "Bob (Sweden)",
+            "Bob (Denmark)",
+            "Alice (Sweden)",
+        ],
+        load_all(json!([{ "field": "NAME", "direction": "DESC" }])),
+    );
+    assert_eq!(
+        vec![
+            "Bob (Denmark)",
+            "Carol (Denmark)",
+            "Alice (Sweden)",
+            "Bob (Sweden)",
+        ],
+        load_all(json!([{ "field": "COUNTRY_NAME" }, { "field": "NAME" }])),
+    );
+    assert_eq!(
+        vec![
+            "Alice (Sweden)",
+            "Bob (Denmark)",
+            "Bob (Sweden)",
+            "Carol (Denmark)",
+        ],
+        load_all(json!([{ "field": "NAME" }, { "field": "COUNTRY_NAME" }])),
+    );
+
+    // A cursor from one ordering isn't valid for another
+    let vars = json!({
+        "orderBy": [{ "field": "NAME" }],
+        "after": encode_cursor(&[1.into()]),
+    });
+    let (json, status) = make_request(&client, query, Some(vars));
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "errors": [{ "extensions": { "code": "INVALID_CURSOR" } }],
+        }),
+        actual: json,
+    );
+
+    // The connections of countries are loaded together, with `direction` left out
+    let query = r#"
+        {
+            countries {
+                name
+                userConnections(orderBy: [{ field: NAME }]) { edges { node { name } } }
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "countries": [
+                    {
+                        "name": "Denmark",
+                        "userConnections": {
+                            "edges": [{ "node": { "name": "Bob" } }, { "node": { "name": "Carol" } }],
+                        },
+                    },
+                    {
+                        "name": "Sweden",
+                        "userConnections": {
+                            "edges": [{ "node": { "name": "Alice" } }, { "node": { "name": "Bob" } }],
+                        },
+                    },
+                ],
+            },
+        }),
+        json,
+    );
+}
+
+#[test]
+fn test_searching_users() {
+    let client = setup();
+
+    {
+        let con = get_db_con(&client);
+        let country = CountryFactory::default().name("Denmark").insert(&con);
+        for name in &["Bob Smith", "Alice Smith", "Alicia Keys", "Smith Smithson"] {
+            UserFactory::default()
+                .name(*name)
+                .country(&country)
+                .insert(&con);
+        }
+    }
+
+    let query = r#"
+        query Test($query: String!, $after: Cursor) {
+            searchUsers(query: $query, first: 1, after: $after) {
+                edges {
+                    node {
+                        name
+                        country {
+                            name
+                        }
+                    }
+                }
+                pageInfo {
+                    endCursor
+                    hasNextPage
+                }
+                totalCount
+            }
+        }
+        "#;
+    let search = |search_query: &str| {
+        let mut names = Vec::new();
+        let mut after = Value::Null;
+        loop {
+            let vars = json!({ "query": search_query, "after": after });
+            let (json, status) = make_request(&client, query, Some(vars));
+            assert_eq!(Status::Ok, status);
+
+            let connection = &json["data"]["searchUsers"];
+            let edges = connection["edges"]
+                .as_array()
+                .unwrap_or_else(|| panic!("unexpected response {}", json));
+            for edge in edges {
+                assert_eq!(json!("Denmark"), edge["node"]["country"]["name"]);
+                names.push(edge["node"]["name"].as_str().unwrap().to_string());
+            }
+
+            if connection["pageInfo"]["hasNextPage"] == json!(false) {
+                return names;
+            }
+            after = connection["pageInfo"]["endCursor"].clone();
+        }
+    };
+
+    assert_eq!(vec!["Alice Smith", "Alicia Keys"], search("ali"));
+    assert_eq!(vec!["Alice Smith"], search("SMI ali"));
+    // Users matching more often rank higher
+    assert_eq!(
+        vec!["Smith Smithson", "Bob Smith", "Alice Smith"],
+        search("smith"),
+    );
+    assert_eq!(Vec::<String>::new(), search("carol"));
+
+    let (json, status) = make_request(&client, query, Some(json!({ "query": " & !" })));
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "errors": [{ "extensions": { "code": "BAD_USER_INPUT" } }],
+        }),
+        actual: json,
+    );
+}
+
+#[test]
+fn test_user_connections_use_a_single_query() {
+    let client = setup();
+
+    let user = {
+        let con = get_db_con(&client);
+        UserFactory::default().insert(&con);
+        let user = UserFactory::default().insert(&con);
+        UserFactory::default().insert(&con);
+        user
+    };
+
+    let query = r#"
+        query Test($after: Cursor) {
+            userConnections(first: 1, after: $after) {
+                edges {
+                    node {
+                        name
+                    }
+                }
+                pageInfo {
+                    hasNextPage
+                    hasPreviousPage
+                }
+                totalCount
+            }
+        }
+        "#;
+    let vars = json!({ "after": encode_cursor(&[(user.id - 1).into()]) });
+
+    let scans_before = table_scans(&client, "users");
+    let (json, status) = make_request(&client, query, Some(vars));
+    let scans_after = table_scans(&client, "users");
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "userConnections": {
+                    "edges": [{ "node": { "name": user.name } }],
+                    "pageInfo": {
+                        "hasNextPage": true,
+                        "hasPreviousPage": true,
+                    },
+                    "totalCount": 3,
+                },
+            },
+        }),
+        json,
+    );
+    assert_eq!(1, scans_after - scans_before);
+}
+
+#[test]
+fn test_approximate_total_count() {
+    let client = setup();
+
+    {
+        let con = get_db_con(&client);
+        for _ in 0..3 {
+            UserFactory::default().insert(&con);
+        }
+        // Updates the estimate in `pg_class.reltuples`
+        diesel::sql_query("ANALYZE users").execute(&*con).unwrap();
+        UserFactory::default().name("Alice").insert(&con);
+    }
+
+    let query = r#"
+        {
+            exact: userConnections(first: 1) {
+                totalCount
+            }
+            approximate: userConnections(first: 1) {
+                totalCount(approximate: true)
+            }
+            filtered: userConnections(first: 1, filter: { name: { equals: "Alice" } }) {
+                totalCount(approximate: true)
+            }
+            uncounted: userConnections(first: 1) {
+                edges {
+                    node {
+                        name
+                    }
+                }
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "exact": { "totalCount": 4 },
+                "approximate": { "totalCount": 3 },
+                "filtered": { "totalCount": 1 },
+                "uncounted": { "edges": [{ "node": { "name": "Bob" } }] },
+            },
+        }),
+        json,
+    );
+}
+
+#[test]
+fn test_paginating_users() {
+    let client = setup();
+
+    let (user_1, user_2, user_3) = {
+        let con = get_db_con(&client);
+        let user_1 = UserFactory::default().name("1").insert(&con);
+        let user_2 = UserFactory::default().name("2").insert(&con);
+        let user_3 = UserFactory::default().name("3").insert(&con);
+        (user_1, user_2, user_3)
+    };
+
+    let query = r#"
+        {
+            userConnections(first: 1) {
+                edges {
+                    cursor
+                    node {
+                        id
+                        name
+                    }
+                }
+                pageInfo {
+                    startCursor
+                    endCursor
+                    hasNextPage
+                }
+                totalCount
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": {
+                "userConnections": {
+                    "edges": [
+                        {
+                            "cursor": encode_cursor(&[user_1.id.into()]),
+                            "node": { "name": user_1.name },
+                        }
+                    ],
+                    "pageInfo": {
+                        "startCursor": encode_cursor(&[user_1.id.into()]),
+                        "endCursor": encode_cursor(&[user_1.id.into()]),
+                        "hasNextPage": true,
+                    },
+                    "totalCount": 3,
+                }
+            },
+        }),
+        actual: json.clone(),
+    );
+    let cursor = json["data"]["userConnections"]["pageInfo"]["endCursor"]
+        .as_str()
+        .unwrap();
+
+    let query = r#"
+        query Test($after: Cursor!) {
+            userConnections(first: 1, after: $after) {
+                edges {
+                    cursor
+                    node {
+                        id
+                        name
+                    }
+                }
+                pageInfo {
+                    startCursor
+                    endCursor
+                    hasNextPage
+                }
+                totalCount
+            }
+        }
+        "#;
+    let vars = json!({ "after": cursor });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": {
+                "userConnections": {
+                    "edges": [
+                        {
+                            "cursor": encode_cursor(&[user_2.id.into()]),
+                            "node": { "name": user_2.name },
+                        }
+                    ],
+                    "pageInfo": {
+                        "startCursor": encode_cursor(&[user_2.id.into()]),
+                        "endCursor": encode_cursor(&[user_2.id.into()]),
+                        "hasNextPage": true,
+                    },
+                    "totalCount": 3,
+                }
+            },
+        }),
+        actual: json.clone(),
+    );
+    let cursor = json["data"]["userConnections"]["pageInfo"]["endCursor"]
+        .as_str()
+        .unwrap();
+
+    let vars = json!({ "after": cursor });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": {
+                "userConnections": {
+                    "edges": [
+                        {
+                            "cursor": encode_cursor(&[user_3.id.into()]),
+                            "node": { "name": user_3.name },
+                        }
+                    ],
+                    "pageInfo": {
+                        "startCursor": encode_cursor(&[user_3.id.into()]),
+                        "endCursor": encode_cursor(&[user_3.id.into()]),
+                        "hasNextPage": false,
+                    },
+                    "totalCount": 3,
+                }
+            },
+        }),
+        actual: json.clone(),
+    );
+    let cursor = json["data"]["userConnections"]["pageInfo"]["endCursor"]
+        .as_str()
+        .unwrap();
+
+    let vars = json!({ "after": cursor });
+    let (json, status) = make_request(&client, query, Some(vars));
+    assert_eq!(Status::Ok, status);
+    let edges = json["data"]["userConnections"]["edges"].as_array().unwrap();
+    assert_eq!(edges.len(), 0);
+}
+
+#[test]
+fn test_paginating_users_backwards() {
+    let client = setup();
+
+    let (user_1, user_2, user_3) = {
+        let con = get_db_con(&client);
+        let user_1 = UserFactory::default().name("1").insert(&con);
+        let user_2 = UserFactory::default().name("2").insert(&con);
+        let user_3 = UserFactory::default().name("3").insert(&con);
+        (user_1, user_2, user_3)
+    };
+
+    let query = r#"
+        query Test($before: Cursor) {
+            userConnections(last: 2, before: $before) {
+                edges {
+                    cursor
+                    node {
+                        name
+                    }
+                }
+                pageInfo {
+                    startCursor
+                    endCursor
+                    hasNextPage
+                    hasPreviousPage
+                }
+                totalCount
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "userConnections": {
+                    "edges": [
+                        {
+                            "cursor": encode_cursor(&[user_2.id.into()]),
+                            "node": { "name": user_2.name },
+                        },
+                        {
+                            "cursor": encode_cursor(&[user_3.id.into()]),
+                            "node": { "name": user_3.name },
+                        },
+                    ],
+                    "pageInfo": {
+                        "startCursor": encode_cursor(&[user_2.id.into()]),
+                        "endCursor": encode_cursor(&[user_3.id.into()]),
+                        "hasNextPage": false,
+                        "hasPreviousPage": true,
+                    },
+                    "totalCount": 3,
+                }
+            },
+        }),
+        json.clone(),
+    );
+
+    let vars = json!({ "before": json["data"]["userConnections"]["pageInfo"]["startCursor"] });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "userConnections": {
+                    "edges": [
+                        {
+                            "cursor": encode_cursor(&[user_1.id.into()]),
+                            "node": { "name": user_1.name },
+                        },
+                    ],
+                    "pageInfo": {
+                        "startCursor": encode_cursor(&[user_1.id.into()]),
+                        "endCursor": encode_cursor(&[user_1.id.into()]),
+                        "hasNextPage": true,
+                        "hasPreviousPage": false,
+                    },
+                    "totalCount": 3,
+                }
+            },
+        }),
+        json,
+    );
+}
+
+#[test]
+fn test_paginating_users_is_stable_when_rows_are_deleted() {
+    let client = setup();
+
+    let (user_1, user_2) = {
+        let con = get_db_con(&client);
+        let user_1 = UserFactory::default().name("1").insert(&con);
+        let user_2 = UserFactory::default().name("2").insert(&con);
+        UserFactory::default().name("3").insert(&con);
+        (user_1, user_2)
+    };
+
+    let query = r#"
+        query Test($after: Cursor) {
+            userConnections(first: 1, after: $after) {
+                edges {
+                    node {
+                        name
+                    }
+                }
+                pageInfo {
+                    endCursor
+                }
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::Ok, status);
+    let cursor = json["data"]["userConnections"]["pageInfo"]["endCursor"].clone();
+
+    {
+        let con = get_db_con(&client);
+        diesel::delete(crate::schema::users::table.find(user_1.id))
+            .execute(&*con)
+            .unwrap();
+    }
+
+    let vars = json!({ "after": cursor });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "userConnections": {
+                    "edges": [{ "node": { "name": user_2.name } }],
+                    "pageInfo": { "endCursor": encode_cursor(&[user_2.id.into()]) },
+                },
+            },
+        }),
+        json,
+    );
+}
+
+#[test]
+fn test_paginating_countries() {
+    let client = setup();
+
+    let (country_1, country_2) = {
+        let con = get_db_con(&client);
+        let country_1 = CountryFactory::default().name("Copenhagen").insert(&con);
+        let country_2 = CountryFactory::default().name("Berlin").insert(&con);
+        (country_1, country_2)
+    };
+
+    let query = r#"
+        query Test($after: Cursor) {
+            countryConnections(first: 1, after: $after) {
+                edges {
+                    cursor
+                    node {
+                        name
+                    }
+                }
+                pageInfo {
+                    endCursor
+                    hasNextPage
+                }
+                totalCount
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "countryConnections": {
+                    "edges": [
+                        {
+                            "cursor": encode_cursor(&[country_1.id.into()]),
+                            "node": { "name": country_1.name },
+                        },
+                    ],
+                    "pageInfo": {
+                        "endCursor": encode_cursor(&[country_1.id.into()]),
+                        "hasNextPage": true,
+                    },
+                    "totalCount": 2,
+                }
+            },
+        }),
+        json.clone(),
+    );
+
+    let vars = json!({ "after": json["data"]["countryConnections"]["pageInfo"]["endCursor"] });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": {
+                "countryConnections": {
+                    "edges": [{ "node": { "name": country_2.name } }],
+                    "pageInfo": { "hasNextPage": false },
+                }
+            },
+        }),
+        actual: json,
+    );
+}
+
+#[test]
+fn test_paginating_with_invalid_cursor() {
+    let client = setup();
+
+    let query = r#"
+        {
+            userConnections(after: "abc") {
+                totalCount
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": { "userConnections": null },
+            "errors": [
+                {
+                    "message": "Invalid cursor `abc`",
+                    "extensions": { "code": "INVALID_CURSOR" },
+                },
+            ],
+        }),
+        actual: json,
+    );
+
+    {
+        let con = get_db_con(&client);
+        CountryFactory::default().insert(&con);
+    }
+
+    // The error is on the eager loaded connection rather than on the countries
+    let query = r#"
+        {
+            countries {
+                userConnections(after: "abc") {
+                    totalCount
+                }
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": { "countries": [{ "userConnections": null }] },
+            "errors": [
+                {
+                    "message": "Invalid cursor `abc`",
+                    "path": ["countries", "userConnections"],
+                    "extensions": { "code": "INVALID_CURSOR" },
+                },
+            ],
+        }),
+        actual: json,
+    );
+}
+
+#[test]
+fn test_paginating_with_page_size_out_of_range() {
+    let client = setup();
+
+    for first in &[0, -1, 1000] {
+        let query = r#"
+            query Test($first: Int) {
+                userConnections(first: $first) {
+                    totalCount
+                }
+            }
+            "#;
+        let vars = json!({ "first": first });
+        let (json, status) = make_request(&client, query, Some(vars));
+
+        assert_eq!(Status::Ok, status);
+        assert_json_include!(
+            expected: json!({
+                "data": { "userConnections": null },
+                "errors": [
+                    {
+                        "message": format!("`first` must be between 1 and 100, got {}", first),
+                        "extensions": {
+                            "code": "PAGE_SIZE_OUT_OF_RANGE",
+                            "min": 1,
+                            "max": 100,
+                        },
+                    },
+                ],
+            }),
+            actual: json,
+        );
+    }
+}
+
+#[test]
+fn test_paginating_users_with_no_users() {
+    let client = setup();
+
+    let query = r#"
+        {
+            userConnections {
+                edges {
+                    cursor
+                    node {
+                        id
+                    }
+                }
+                pageInfo {
+                    startCursor
+                    endCursor
+                    hasNextPage
+                }
+            }
+        }
+        "#;
+
+    let (json, status) = make_request(&client, query, None);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": {
+                "userConnections": {
+                    "edges": [],
+                    "pageInfo": {
+                        "startCursor": null,
+                        "endCursor": null,
+                        "hasNextPage": false,
+                    }
+                }
+            },
+        }),
+        actual: json.clone(),
+    );
+    let edges = json["data"]["userConnections"]["edges"].as_array().unwrap();
+    assert_eq!(edges.len(), 0);
+}
+
+#[test]
+fn test_creating_user() {
+    let client = setup();
+
+    let country = {
+        let con = get_db_con(&client);
+        CountryFactory::default().insert(&con)
+    };
+
+    let query = r#"
+        mutation Test($countryId: ID!) {
+            createUser(input: { name: "Alice", countryId: $countryId }) {
+                name
+                country {
+                    id
+                    name
+                }
+            }
+        }
+        "#;
+    let vars = json!({ "countryId": country_id(&country) });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "createUser": {
+                    "name": "Alice",
+                    "country": {
+                        "id": country_id(&country),
+                        "name": country.name,
+                    },
+                },
+            },
+        }),
+        json,
+    );
+
+    let con = get_db_con(&client);
+    let names = crate::schema::users::table
+        .select(crate::schema::users::name)
+        .load::<String>(&*con)
+        .unwrap();
+    assert_eq!(names, vec!["Alice".to_string()]);
+}
+
+#[test]
+fn test_updating_user() {
+    let client = setup();
+
+    let (user, other_country) = {
+        let con = get_db_con(&client);
+        let user = UserFactory::default().name("Bob").insert(&con);
+        let other_country = CountryFactory::default().name("Berlin").insert(&con);
+        (user, other_country)
+    };
+
+    let query = r#"
+        mutation Test($id: ID!, $countryId: ID!) {
+            updateUser(id: $id, input: { countryId: $countryId }) {
+                id
+                name
+                country {
+                    name
+                }
+            }
+        }
+        "#;
+    let vars = json!({
+        "id": user_id(&user),
+        "countryId": country_id(&other_country),
+    });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "updateUser": {
+                    "id": user_id(&user),
+                    "name": "Bob",
+                    "country": { "name": "Berlin" },
+                },
+            },
+        }),
+        json,
+    );
+}
+
+#[test]
+fn test_deleting_user() {
+    let client = setup();
+
+    let user = {
+        let con = get_db_con(&client);
+        UserFactory::default().insert(&con)
+    };
+
+    let query = r#"
+        mutation Test($id: ID!) {
+            deleteUser(id: $id) {
+                id
+                country {
+                    name
+                }
+            }
+        }
+        "#;
+    let vars = json!({ "id": user_id(&user) });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "deleteUser": {
+                    "id": user_id(&user),
+                    "country": { "name": "Copenhagen" },
+                },
+            },
+        }),
+        json,
+    );
+
+    let (json, _) = make_request(&client, "{ users { id } }", None);
+    assert_json_eq!(json!({ "data": { "users": [] } }), json);
+}
+
+#[test]
+fn test_errors_have_codes() {
+    let client = setup();
+
+    let query = r#"
+        mutation Test($id: ID!) {
+            deleteUser(id: $id) {
+                id
+            }
+        }
+        "#;
+
+    let (json, status) = make_request(
+        &client,
+        query,
+        Some(json!({ "id": global_id::encode(NodeType::User, 123) })),
+    );
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "errors": [
+                {
+                    "message": "User with id `123` was not found",
+                    "extensions": { "code": "NOT_FOUND" },
+                },
+            ],
+        }),
+        actual: json,
+    );
+
+    let (json, status) = make_request(&client, query, Some(json!({ "id": "abc" })));
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "errors": [
+                {
+                    "message": "Invalid ID `abc`",
+                    "extensions": { "code": "INVALID_ID" },
+                },
+            ],
+        }),
+        actual: json,
+    );
+}
+
+#[test]
+fn test_creating_and_renaming_country() {
+    let client = setup();
+
+    let query = r#"
+        mutation {
+            createCountry(input: { name: "Copenhagen" }) {
+                id
+                name
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({ "data": { "createCountry": { "name": "Copenhagen" } } }),
+        actual: json.clone(),
+    );
+    let id = json["data"]["createCountry"]["id"].as_str().unwrap();
+
+    let query = r#"
+        mutation Test($id: ID!) {
+            renameCountry(id: $id, name: "Aarhus") {
+                id
+                name
+            }
+        }
+        "#;
+    let vars = json!({ "id": id });
+    let (json, status) = make_request(&client, query, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({ "data": { "renameCountry": { "id": id, "name": "Aarhus" } } }),
+        json,
+    );
+}
+
+const DELETE_COUNTRY_QUERY: &str = r#"
+    mutation Test($id: ID!, $strategy: DeleteCountryStrategy!, $reassignTo: ID) {
+        deleteCountry(id: $id, strategy: $strategy, reassignTo: $reassignTo) {
+            outcome
+            affectedUsersCount
+            country {
+                name
+            }
+        }
+    }
+"#;
+
+#[test]
+fn test_deleting_country_with_users_is_rejected() {
+    let client = setup();
+
+    let country = {
+        let con = get_db_con(&client);
+        let country = CountryFactory::default().insert(&con);
+        UserFactory::default().country(&country).insert(&con);
+        country
+    };
+
+    let vars = json!({ "id": country_id(&country), "strategy": "REJECT" });
+    let (json, status) = make_request(&client, DELETE_COUNTRY_QUERY, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "deleteCountry": {
+                    "outcome": "REJECTED",
+                    "affectedUsersCount": 1,
+                    "country": { "name": country.name },
+                },
+            },
+        }),
+        json,
+    );
+
+    let con = get_db_con(&client);
+    let count = crate::schema::countries::table
+        .count()
+        .get_result::<i64>(&*con)
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn test_deleting_country_reassigning_users() {
+    let client = setup();
+
+    let (country, other_country, user) = {
+        let con = get_db_con(&client);
+        let country = CountryFactory::default().insert(&con);
+        let other_country = CountryFactory::default().name("Berlin").insert(&con);
+        let user = UserFactory::default().country(&country).insert(&con);
+        (country, other_country, user)
+    };
+
+    let vars = json!({
+        "id": country_id(&country),
+        "strategy": "REASSIGN",
+        "reassignTo": country_id(&other_country),
+    });
+    let (json, status) = make_request(&client, DELETE_COUNTRY_QUERY, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": {
+                "deleteCountry": {
+                    "outcome": "REASSIGNED",
+                    "affectedUsersCount": 1,
+                },
+            },
+        }),
+        actual: json,
+    );
+
+    let con = get_db_con(&client);
+    let country_id = crate::schema::users::table
+        .find(user.id)
+        .select(crate::schema::users::country_id)
+        .first::<i32>(&*con)
+        .unwrap();
+    assert_eq!(country_id, other_country.id);
+}
+
+#[test]
+fn test_deleting_country_cascading_to_users() {
+    let client = setup();
+
+    let country = {
+        let con = get_db_con(&client);
+        let country = CountryFactory::default().insert(&con);
+        UserFactory::default().country(&country).insert(&con);
+        UserFactory::default().country(&country).insert(&con);
+        country
+    };
+
+    let vars = json!({ "id": country_id(&country), "strategy": "CASCADE" });
+    let (json, status) = make_request(&client, DELETE_COUNTRY_QUERY, Some(vars));
+
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": {
+                "deleteCountry": {
+                    "outcome": "CASCADED",
+                    "affectedUsersCount": 2,
+                },
+            },
+        }),
+        actual: json,
+    );
+
+    let (json, _) = make_request(&client, "{ users { id } }", None);
+    assert_json_eq!(json!({ "data": { "users": [] } }), json);
+}
+
+#[test]
+fn test_subscribing_to_user_changes() {
+    let client = setup();
+
+    let user = {
+        let con = get_db_con(&client);
+        UserFactory::default().insert(&con)
+    };
+    let session = Session::from_rocket(client.rocket())
+        .expect("subscription session")
+        .authenticated_as(Some(admin()));
+    let changes = client.rocket().state::<UserChanges>().unwrap().clone();
+
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let (sender, incoming) = mpsc::unbounded();
+        let (outgoing, mut messages) = mpsc::unbounded();
+        rocket::tokio::spawn(session.serve(incoming, outgoing));
+
+        send_ws_message(&sender, json!({ "type": "connection_init" }));
+        assert_eq!(
+            json!({ "type": "connection_ack" }),
+            next_ws_message(&mut messages).await
+        );
+
+        let subscribe = |id: &str, query: &str| {
+            send_ws_message(
+                &sender,
+                json!({ "type": "subscribe", "id": id, "payload": { "query": query } }),
+            )
+        };
+        subscribe("1", "subscription { userCreated { id name } }");
+        subscribe("2", "subscription Deleted { deletedId: userDeleted }");
+        subscribe("3", "subscription { userRenamed { id } }");
+        assert_json_include!(
+            expected: json!({ "type": "error", "id": "3" }),
+            actual: next_ws_message(&mut messages).await,
+        );
+
+        // Messages are handled in order so once we get the pong the subscriptions have started.
+        send_ws_message(&sender, json!({ "type": "ping" }));
+        assert_eq!(
+            json!({ "type": "pong" }),
+            next_ws_message(&mut messages).await
+        );
+
+        changes.publish(UserChange {
+            kind: ChangeKind::Created,
+            id: user.id,
+        });
+        assert_json_eq!(
+            json!({
+                "type": "next",
+                "id": "1",
+                "payload": {
+                    "data": {
+                        "userCreated": { "id": user_id(&user), "name": user.name },
+                    },
+                },
+            }),
+            next_ws_message(&mut messages).await,
+        );
+
+        changes.publish(UserChange {
+            kind: ChangeKind::Deleted,
+            id: user.id,
+        });
+        assert_json_eq!(
+            json!({
+                "type": "next",
+                "id": "2",
+                "payload": {
+                    "data": { "deletedId": user_id(&user) },
+                },
+            }),
+            next_ws_message(&mut messages).await,
+        );
+
+        send_ws_message(&sender, json!({ "type": "complete", "id": "1" }));
+        changes.publish(UserChange {
+            kind: ChangeKind::Created,
+            id: user.id,
+        });
+        send_ws_message(&sender, json!({ "type": "ping" }));
+        assert_eq!(
+            json!({ "type": "pong" }),
+            next_ws_message(&mut messages).await
+        );
+    });
+}
+
+#[test]
+fn test_parsing_user_changes_from_the_trigger() {
+    use diesel::{dsl::sql, sql_types::Text};
+
+    let client = setup();
+    let con = get_db_con(&client);
+
+    // Built like `notify_user_change` builds its payload, since Postgres formats the JSON.
+    for (op, kind) in [
+        ("INSERT", ChangeKind::Created),
+        ("UPDATE", ChangeKind::Updated),
+        ("DELETE", ChangeKind::Deleted),
+    ] {
+        let payload = diesel::select(sql::<Text>(&format!(
+            "json_build_object('op', '{}', 'id', 1)::text",
+            op
+        )))
+        .get_result::<String>(&con)
+        .unwrap();
+        let change = serde_json::from_str::<UserChange>(&payload).unwrap();
+        assert_eq!(UserChange { kind, id: 1 }, change);
+    }
+}
+
+#[test]
+fn test_authenticating_with_bearer_tokens() {
+    let client = setup_with_figment(
+        rocket::Config::figment()
+            .merge(("auth.allow_anonymous", false))
+            .merge(("auth.hs256_secret", TEST_SECRET)),
+    );
+    let query = "{ countries { id } }";
+
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, None));
+    let (json, status) = make_request_as(&client, Some(authorization), query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    let (json, status) = make_request_as(&client, None, query, None);
+    assert_eq!(Status::Unauthorized, status);
+    assert_json_eq!(
+        json!({
+            "errors": [{
+                "message": "Missing bearer token",
+                "extensions": { "code": "UNAUTHORIZED" },
+            }],
+        }),
+        json,
+    );
+
+    for authorization in [
+        format!("Bearer {}", token("other secret", 60, None)),
+        format!("Bearer {}", token(TEST_SECRET, -3600, None)),
+        format!("Bearer {}", token(TEST_SECRET, 60, Some("superuser"))),
+        format!("Basic {}", token(TEST_SECRET, 60, None)),
+    ] {
+        let (json, status) = make_request_as(&client, Some(authorization), query, None);
+        assert_eq!(Status::Unauthorized, status);
+        assert_json_include!(
+            expected: json!({ "errors": [{ "extensions": { "code": "UNAUTHORIZED" } }] }),
+            actual: json,
+        );
+    }
+
+    // Browsers can't set headers on WebSockets so they authenticate in `connection_init`.
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    let admin_token = format!("Bearer {}", token(TEST_SECRET, 60, Some("admin")));
+    for (payload, accepted) in [
+        (json!({ "Authorization": admin_token }), true),
+        (json!({}), false),
+        (
+            json!({ "Authorization": format!("Bearer {}", token("other secret", 60, None)) }),
+            false,
+        ),
+    ] {
+        let session = Session::from_rocket(client.rocket()).expect("subscription session");
+        runtime.block_on(async {
+            let (sender, incoming) = mpsc::unbounded();
+            let (outgoing, mut messages) = mpsc::unbounded();
+            rocket::tokio::spawn(session.serve(incoming, outgoing));
+
+            send_ws_message(
+                &sender,
+                json!({ "type": "connection_init", "payload": payload }),
+            );
+            if !accepted {
+                match messages.next().await {
+                    Some(Message::Close(Some(frame))) => assert_eq!(4403, u16::from(frame.code)),
+                    message => panic!("expected the socket to close, got {:?}", message),
+                }
+                return;
+            }
+            assert_eq!(
+                json!({ "type": "connection_ack" }),
+                next_ws_message(&mut messages).await
+            );
+
+            // Only admins may subscribe to created users.
+            send_ws_message(
+                &sender,
+                json!({
+                    "type": "subscribe",
+                    "id": "1",
+                    "payload": { "query": "subscription { userCreated { id } }" },
+                }),
+            );
+            send_ws_message(&sender, json!({ "type": "ping" }));
+            assert_eq!(
+                json!({ "type": "pong" }),
+                next_ws_message(&mut messages).await
+            );
+        });
+    }
+}
+
+#[test]
+fn test_anonymous_access() {
+    let client =
+        setup_with_figment(rocket::Config::figment().merge(("auth.allow_anonymous", true)));
+    let query = "{ countries { id } }";
+
+    let (json, status) = make_request_as(&client, None, query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    // Tokens are still verified when they're sent.
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, None));
+    let (_, status) = make_request_as(&client, Some(authorization), query, None);
+    assert_eq!(Status::Unauthorized, status);
+}
+
+#[test]
+fn test_anonymous_access_is_only_allowed_in_debug_builds() {
+    let allow_anonymous = |profile: &str| {
+        rocket::Config::figment()
+            .select(profile)
+            .extract_inner::<bool>("auth.allow_anonymous")
+            .unwrap()
+    };
+    assert!(allow_anonymous("debug"));
+    assert!(!allow_anonymous("release"));
+}
+
+#[test]
+fn test_fields_requiring_a_role() {
+    let client = setup();
+
+    let country = {
+        let con = get_db_con(&client);
+        CountryFactory::default().insert(&con)
+    };
+
+    let query = "{ users { id } countries { id } }";
+    let forbidden = json!({
+        "data": {
+            "users": null,
+            "countries": [{ "id": country_id(&country) }],
+        },
+        "errors": [{
+            "message": "`Query.users` requires the ADMIN role",
+            "locations": [{ "line": 1, "column": 3 }],
+            "path": ["users"],
+            "extensions": { "code": "FORBIDDEN" },
+        }],
+    });
+
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, Some("user")));
+    let (json, status) = make_request_as(&client, Some(authorization), query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(forbidden.clone(), json);
+
+    let (json, status) = make_request_as(&client, None, query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(forbidden, json);
+
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "data": {
+                "users": [],
+                "countries": [{ "id": country_id(&country) }],
+            },
+        }),
+        json,
+    );
+
+    let query = r#"mutation { createCountry(input: { name: "Denmark" }) { id } }"#;
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, Some("user")));
+    let (json, status) = make_request_as(&client, Some(authorization), query, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_include!(
+        expected: json!({
+            "data": { "createCountry": null },
+            "errors": [{
+                "message": "`Mutation.createCountry` requires the ADMIN role",
+                "extensions": { "code": "FORBIDDEN" },
+            }],
+        }),
+        actual: json,
+    );
+
+    use crate::schema::countries;
+    let user = {
+        let con = get_db_con(&client);
+        let count = countries::table.count().get_result::<i64>(&con).unwrap();
+        assert_eq!(1, count);
+        UserFactory::default().country(&country).insert(&con)
+    };
+
+    // Every field that returns users in bulk requires an admin.
+    let user_id = user_id(&user).to_string();
+    for (query, data, field, path) in [
+        (
+            "{ userConnections { edges { node { name } } } }".to_string(),
+            json!({ "userConnections": null }),
+            "Query.userConnections",
+            json!(["userConnections"]),
+        ),
+        (
+            r#"{ searchUsers(query: "a") { edges { node { name } } } }"#.to_string(),
+            json!({ "searchUsers": null }),
+            "Query.searchUsers",
+            json!(["searchUsers"]),
+        ),
+        (
+            format!(r#"{{ node(id: "{}") {{ id }} }}"#, user_id),
+            json!({ "node": null }),
+            "Query.node",
+            json!(["node"]),
+        ),
+        (
+            format!(r#"{{ nodes(ids: ["{}"]) {{ id }} }}"#, user_id),
+            json!({ "nodes": null }),
+            "Query.nodes",
+            json!(["nodes"]),
+        ),
+        (
+            "{ countries { users { name } } }".to_string(),
+            json!({ "countries": [{ "users": null }] }),
+            "Country.users",
+            json!(["countries", "users"]),
+        ),
+        (
+            "{ countries { userConnections { edges { node { name } } } } }".to_string(),
+            json!({ "countries": [{ "userConnections": null }] }),
+            "Country.userConnections",
+            json!(["countries", "userConnections"]),
+        ),
+    ] {
+        let (json, status) = make_request_as(&client, None, &query, None);
+        assert_eq!(Status::Ok, status);
+        assert_json_include!(
+            expected: json!({
+                "data": data,
+                "errors": [{
+                    "message": format!("`{}` requires the ADMIN role", field),
+                    "path": path,
+                    "extensions": { "code": "FORBIDDEN" },
+                }],
+            }),
+            actual: json,
+        );
+    }
+
+    // The users of countries aren't even loaded for callers who may not see them.
+    let scans_before = table_scans(&client, "users");
+    let (_, status) = make_request_as(&client, None, "{ countries { users { name } } }", None);
+    assert_eq!(Status::Ok, status);
+    assert_eq!(0, table_scans(&client, "users") - scans_before);
+
+    let session = Session::from_rocket(client.rocket()).expect("subscription session");
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let (sender, incoming) = mpsc::unbounded();
+        let (outgoing, mut messages) = mpsc::unbounded();
+        rocket::tokio::spawn(session.serve(incoming, outgoing));
+
+        send_ws_message(&sender, json!({ "type": "connection_init" }));
+        next_ws_message(&mut messages).await;
+        for (id, field) in [("1", "userCreated"), ("2", "userUpdated")] {
+            let query = format!("subscription {{ {} {{ name }} }}", field);
+            send_ws_message(
+                &sender,
+                json!({ "type": "subscribe", "id": id, "payload": { "query": query } }),
+            );
+            assert_json_eq!(
+                json!({
+                    "type": "error",
+                    "id": id,
+                    "payload": [{
+                        "message": format!("`Subscription.{}` requires the ADMIN role", field),
+                        "extensions": { "code": "FORBIDDEN" },
+                    }],
+                }),
+                next_ws_message(&mut messages).await,
+            );
+        }
+    });
+}
+
+#[test]
+fn test_rejecting_queries_that_are_too_complex() {
+    let client = setup();
+
+    let query = "{ userConnections(first: 100000) { edges { node { name } } } }";
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::BadRequest, status);
+    assert_json_eq!(
+        json!({
+            "errors": [{
+                "message": "Query is too complex. It has a depth of 4 and a cost of 300001, but \
+                            the maximum depth is 15 and the maximum cost is 10000",
+                "extensions": {
+                    "code": "QUERY_TOO_COMPLEX",
+                    "depth": 4,
+                    "cost": 300001,
+                    "maxDepth": 15,
+                    "maxCost": 10000,
+                },
+            }],
+        }),
+        json,
+    );
+
+    // Page sizes from variables and nested connections multiply.
+    let query = r#"
+        query Test($first: Int = 100) {
+            countryConnections(first: $first) {
+                edges { node { ...Users } }
+            }
+        }
+
+        fragment Users on Country {
+            userConnections(first: 50) { edges { node { name } } }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::BadRequest, status);
+    assert_json_include!(
+        expected: json!({ "errors": [{ "extensions": { "depth": 7, "cost": 15301 } }] }),
+        actual: json,
+    );
+    let (json, status) = make_request(&client, query, Some(json!({ "first": 10 })));
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({ "data": { "countryConnections": { "edges": [] } } }),
+        json
+    );
+
+    let nested = (0..8).fold("name".to_string(), |selection, _| {
+        format!("country {{ users {{ {} }} }}", selection)
+    });
+    let query = format!("{{ users {{ {} }} }}", nested);
+    let (json, status) = make_request(&client, &query, None);
+    assert_eq!(Status::BadRequest, status);
+    assert_json_include!(
+        expected: json!({ "errors": [{ "extensions": { "depth": 18, "cost": 1222222221 } }] }),
+        actual: json,
+    );
+
+    // Connections without `first` or `last` count as a page of 20, and lists as 10 items.
+    let query = r#"
+        {
+            countryConnections {
+                edges {
+                    node {
+                        userConnections {
+                            edges { node { country { userConnections { edges { node { name } } } } } }
+                        }
+                    }
+                }
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::BadRequest, status);
+    assert_json_include!(
+        expected: json!({ "errors": [{ "extensions": { "depth": 11, "cost": 25661 } }] }),
+        actual: json,
+    );
+    let query = "{ countries { users { country { name } } } }";
+    let (_, status) = make_request(&client, query, None);
+    assert_eq!(Status::Ok, status);
+
+    // So do connections with a null or unset page size.
+    let query = r#"
+        query Test($first: Int, $last: Int) {
+            countryConnections(first: $first) {
+                edges {
+                    node {
+                        userConnections(first: null) {
+                            edges { node { country { userConnections(last: $last) { edges { node { name } } } } } }
+                        }
+                    }
+                }
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, Some(json!({ "last": null })));
+    assert_eq!(Status::BadRequest, status);
+    assert_json_include!(
+        expected: json!({ "errors": [{ "extensions": { "depth": 11, "cost": 25661 } }] }),
+        actual: json,
+    );
+
+    // `nodes` counts once per ID.
+    let query = "query Test($ids: [ID!]!) { nodes(ids: $ids) { id } }";
+    let ids = (0..10_000).map(|id| id.to_string()).collect::<Vec<_>>();
+    let (json, status) = make_request(&client, query, Some(json!({ "ids": ids })));
+    assert_eq!(Status::BadRequest, status);
+    assert_json_include!(
+        expected: json!({ "errors": [{ "extensions": { "depth": 2, "cost": 10001 } }] }),
+        actual: json,
+    );
+    let query = r#"{ nodes(ids: ["1", "2"]) { id } }"#;
+    let (_, status) = make_request(&client, query, None);
+    assert_eq!(Status::Ok, status);
+
+    // Documents that can't be measured are rejected rather than executed unchecked.
+    let (json, status) = make_request(&client, "{ userConnections(first: 100000) {", None);
+    assert_eq!(Status::BadRequest, status);
+    assert_json_include!(
+        expected: json!({ "errors": [{ "extensions": { "code": "GRAPHQL_PARSE_FAILED" } }] }),
+        actual: json,
+    );
+    let (json, status) = make_request(
+        &client,
+        "query A { users { id } } query B { me { id } }",
+        None,
+    );
+    assert_eq!(Status::BadRequest, status);
+    assert_json_eq!(
+        json!({
+            "errors": [{
+                "message": "Must provide operation name if query contains multiple operations",
+                "extensions": { "code": "OPERATION_RESOLUTION_FAILURE" },
+            }],
+        }),
+        json,
+    );
+}
+
+#[test]
+fn test_configuring_complexity_limits() {
+    let client = setup_with_figment(
+        rocket::Config::figment()
+            .merge(("auth.hs256_secret", TEST_SECRET))
+            .merge(("max_query_depth", 2))
+            .merge(("max_query_cost", 10))
+            .merge(("list_cost_multiplier", 2)),
+    );
+
+    // 1 + 2 * 2, where the default multiplier would make it cost 21.
+    let (json, status) = make_request(&client, "{ countries { id name } }", None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    let query = "{ countryConnections(first: 5) { edges { node { id } } } }";
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::BadRequest, status);
+    assert_json_include!(
+        expected: json!({
+            "errors": [{
+                "extensions": {
+                    "code": "QUERY_TOO_COMPLEX",
+                    "depth": 4,
+                    "cost": 16,
+                    "maxDepth": 2,
+                    "maxCost": 10,
+                },
+            }],
+        }),
+        actual: json,
+    );
+}
+
+#[test]
+fn test_introspection_is_within_the_default_complexity_limits() {
+    let client = setup();
+
+    let query = r#"
+        query IntrospectionQuery {
+            __schema {
+                queryType { name }
+                mutationType { name }
+                types { ...FullType }
+            }
+        }
+
+        fragment FullType on __Type {
+            kind
+            name
+            fields(includeDeprecated: true) {
+                name
+                args { ...InputValue }
+                type { ...TypeRef }
+            }
+            inputFields { ...InputValue }
+            interfaces { ...TypeRef }
+            enumValues(includeDeprecated: true) { name }
+            possibleTypes { ...TypeRef }
+        }
+
+        fragment InputValue on __InputValue {
+            name
+            type { ...TypeRef }
+            defaultValue
+        }
+
+        fragment TypeRef on __Type {
+            kind name ofType {
+                kind name ofType {
+                    kind name ofType {
+                        kind name ofType {
+                            kind name ofType {
+                                kind name ofType {
+                                    kind name ofType {
+                                        kind name
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        "#;
+    let (json, status) = make_request(&client, query, None);
+    assert_eq!(Status::Ok, status, "{}", json);
+}
+
+#[test]
+fn test_automatic_persisted_queries() {
+    let client = setup();
+    let query = "{ countries { name } }";
+    let extensions =
+        json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256_hash(query) } });
+
+    let (json, status) = send_request(&client, None, json!({ "extensions": extensions }));
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!({
+            "errors": [{
+                "message": "PersistedQueryNotFound",
+                "extensions": { "code": "PERSISTED_QUERY_NOT_FOUND" },
+            }],
+        }),
+        json,
+    );
+
+    let body = json!({ "query": query, "extensions": extensions });
+    let (json, status) = send_request(&client, None, body);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    let (json, status) = send_request(&client, None, json!({ "extensions": extensions }));
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    let uri = format!(
+        "/graphql?extensions={}",
+        rocket::http::RawStr::new(&extensions.to_string()).percent_encode(),
+    );
+    let response = client.get(uri).dispatch();
+    assert_eq!(Status::Ok, response.status());
+    let json = serde_json::from_str::<Value>(&response.into_string().unwrap()).unwrap();
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    let body = json!({ "query": "{ countries { id } }", "extensions": extensions });
+    let (json, status) = send_request(&client, None, body);
+    assert_eq!(Status::BadRequest, status);
+    assert_json_eq!(
+        json!({
+            "errors": [{
+                "message": "provided sha does not match query",
+                "extensions": { "code": "BAD_USER_INPUT" },
+            }],
+        }),
+        json,
+    );
 }
 
-fn get_db_con(client: &Client) -> DbCon {
-    DbCon::get_one(client.rocket()).expect("get db con")
+#[test]
+fn test_persisted_queries_stored_in_postgres() {
+    let client = setup_with_figment(
+        rocket::Config::figment()
+            .merge(("auth.hs256_secret", TEST_SECRET))
+            .merge(("persisted_queries.store", "postgres")),
+    );
+    let query = "{ countries { name } }";
+    let extensions =
+        json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256_hash(query) } });
+
+    // Queries that can't be executed aren't registered.
+    for invalid in &[
+        "{ countries {",
+        "{ userConnections(first: 100000) { totalCount } }",
+    ] {
+        let extensions =
+            json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256_hash(invalid) } });
+        let body = json!({ "query": invalid, "extensions": extensions });
+        let (_, status) = send_request(&client, None, body);
+        assert_eq!(Status::BadRequest, status);
+        let (json, _) = send_request(&client, None, json!({ "extensions": extensions }));
+        assert_json_include!(
+            expected: json!({ "errors": [{ "extensions": { "code": "PERSISTED_QUERY_NOT_FOUND" } }] }),
+            actual: json,
+        );
+    }
+
+    let body = json!({ "query": query, "extensions": extensions });
+    let (json, status) = send_request(&client, None, body);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    let stored = {
+        use crate::schema::persisted_queries;
+        let con = get_db_con(&client);
+        persisted_queries::table
+            .select((persisted_queries::sha256_hash, persisted_queries::query))
+            .load::<(String, String)>(&*con)
+            .unwrap()
+    };
+    assert_eq!(vec![(sha256_hash(query), query.to_string())], stored);
+
+    let (json, status) = send_request(&client, None, json!({ "extensions": extensions }));
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
 }
 
-fn make_request(client: &Client, query: &str, variables: Option<Value>) -> (Value, Status) {
-    let mut req = client.post("/graphql").header(ContentType::JSON);
-    req.set_body(
+#[test]
+fn test_allowing_only_queries_from_the_manifest() {
+    let allowed = "{ countries { name } }";
+    let manifest = json!({
+        "format": "apollo-persisted-query-manifest",
+        "version": 1,
+        "operations": [
+            { "id": sha256_hash(allowed), "name": null, "type": "query", "body": allowed },
+        ],
+    });
+    let path = std::env::temp_dir().join(format!(
+        "graphql-app-example-manifest-{}.json",
+        std::process::id()
+    ));
+    std::fs::write(&path, manifest.to_string()).unwrap();
+
+    let client = setup_with_figment(
+        rocket::Config::figment()
+            .merge(("auth.hs256_secret", TEST_SECRET))
+            .merge(("persisted_queries.manifest", &path))
+            .merge(("persisted_queries.allowlist_only", true)),
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    let (json, status) = make_request_as(&client, None, allowed, None);
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    let extensions =
+        json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256_hash(allowed) } });
+    let (json, status) = send_request(&client, None, json!({ "extensions": extensions }));
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!({ "data": { "countries": [] } }), json);
+
+    let other = "{ countries { id } }";
+    let extensions =
+        json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256_hash(other) } });
+    for body in [
+        json!({ "query": other }),
+        json!({ "extensions": extensions }),
+        json!({ "query": other, "extensions": extensions }),
+    ] {
+        let (json, status) = send_request(&client, None, body);
+        assert_eq!(Status::BadRequest, status);
+        assert_json_eq!(
+            json!({
+                "errors": [{
+                    "message": "Only queries from the persisted query manifest are allowed",
+                    "extensions": { "code": "PERSISTED_QUERY_NOT_ALLOWED" },
+                }],
+            }),
+            json,
+        );
+    }
+
+    // Operations sent over WebSocket are held to the same rules
+    let session = Session::from_rocket(client.rocket()).expect("subscription session");
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let (sender, incoming) = mpsc::unbounded();
+        let (outgoing, mut messages) = mpsc::unbounded();
+        rocket::tokio::spawn(session.serve(incoming, outgoing));
+
+        send_ws_message(&sender, json!({ "type": "connection_init" }));
+        assert_eq!(
+            json!({ "type": "connection_ack" }),
+            next_ws_message(&mut messages).await
+        );
+
+        let allowed_extensions =
+            json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256_hash(allowed) } });
+        send_ws_message(
+            &sender,
+            json!({ "type": "subscribe", "id": "1", "payload": { "extensions": allowed_extensions } }),
+        );
+        assert_json_eq!(
+            json!({ "type": "next", "id": "1", "payload": { "data": { "countries": [] } } }),
+            next_ws_message(&mut messages).await,
+        );
+        assert_json_eq!(
+            json!({ "type": "complete", "id": "1" }),
+            next_ws_message(&mut messages).await,
+        );
+
+        send_ws_message(
+            &sender,
+            json!({ "type": "subscribe", "id": "2", "payload": { "query": other } }),
+        );
+        assert_json_eq!(
+            json!({
+                "type": "error",
+                "id": "2",
+                "payload": [{
+                    "message": "Only queries from the persisted query manifest are allowed",
+                    "extensions": { "code": "PERSISTED_QUERY_NOT_ALLOWED" },
+                }],
+            }),
+            next_ws_message(&mut messages).await,
+        );
+    });
+}
+
+#[test]
+fn test_root_fields_share_loaded_rows() {
+    let client = setup();
+
+    let user = {
+        let con = get_db_con(&client);
+        let user = UserFactory::default().insert(&con);
+        UserFactory::default().insert(&con);
+        user
+    };
+
+    let query = r#"
+        query Test($id: ID!) {
+            users {
+                country { name }
+            }
+            userConnections(first: 10) {
+                edges { node { country { name } } }
+            }
+            user(id: $id) {
+                name
+                country { name }
+            }
+        }
+        "#;
+    let vars = json!({ "id": user_id(&user) });
+
+    let users_before = table_scans(&client, "users");
+    let countries_before = table_scans(&client, "countries");
+    let (json, status) = make_request(&client, query, Some(vars));
+    let users_after = table_scans(&client, "users");
+    let countries_after = table_scans(&client, "countries");
+
+    assert_eq!(Status::Ok, status);
+    let country = json!({ "country": { "name": "Copenhagen" } });
+    assert_json_eq!(
         json!({
-            "query": query,
-            "variables": variables.unwrap_or_else(|| json!({})),
+            "data": {
+                "users": [country, country],
+                "userConnections": {
+                    "edges": [{ "node": country }, { "node": country }],
+                },
+                "user": { "name": user.name, "country": { "name": "Copenhagen" } },
+            },
+        }),
+        json,
+    );
+    // `user` is found among the users loaded by `users`, and the countries are only loaded once.
+    assert_eq!(2, users_after - users_before);
+    assert_eq!(1, countries_after - countries_before);
+}
+
+#[test]
+fn test_batched_requests() {
+    let client = setup();
+
+    let user = {
+        let con = get_db_con(&client);
+        UserFactory::default().insert(&con)
+    };
+
+    let query = "{ countries { name users { name } } }";
+    let body = json!([
+        { "query": query },
+        { "query": query },
+        { "query": "{ nope }" },
+    ]);
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, Some("admin")));
+
+    let scans_before = table_scans(&client, "users");
+    let (json, status) = send_request(&client, Some(authorization), body);
+    let scans_after = table_scans(&client, "users");
+
+    assert_eq!(Status::Ok, status);
+    let countries = json!({
+        "data": {
+            "countries": [{ "name": "Copenhagen", "users": [{ "name": user.name }] }],
+        },
+    });
+    assert_json_include!(
+        expected: json!([
+            countries,
+            countries,
+            { "errors": [{ "message": "Unknown field \"nope\" on type \"Query\"" }] },
+        ]),
+        actual: json,
+    );
+    // The users of the countries are only loaded by the first operation.
+    assert_eq!(1, scans_after - scans_before);
+}
+
+#[test]
+fn test_batched_operations_see_changes_made_by_earlier_mutations() {
+    let client = setup();
+
+    let country = {
+        let con = get_db_con(&client);
+        CountryFactory::default().insert(&con)
+    };
+
+    let query = "{ countries { users { name } } }";
+    let body = json!([
+        { "query": query },
+        {
+            "query": r#"
+                mutation Test($countryId: ID!) {
+                    createUser(input: { name: "Alice", countryId: $countryId }) { name }
+                }
+            "#,
+            "variables": { "countryId": country_id(&country) },
+        },
+        { "query": query },
+    ]);
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, Some("admin")));
+    let (json, status) = send_request(&client, Some(authorization), body);
+
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(
+        json!([
+            { "data": { "countries": [{ "users": [] }] } },
+            { "data": { "createUser": { "name": "Alice" } } },
+            { "data": { "countries": [{ "users": [{ "name": "Alice" }] }] } },
+        ]),
+        json,
+    );
+}
+
+#[test]
+fn test_batch_size_limit() {
+    let client = setup_with_figment(
+        rocket::Config::figment()
+            .merge(("auth.hs256_secret", TEST_SECRET))
+            .merge(("max_batch_size", 2)),
+    );
+
+    let (json, status) = send_request(&client, None, json!([{ "query": "{ countries { id } }" }]));
+    assert_eq!(Status::Ok, status);
+    assert_json_eq!(json!([{ "data": { "countries": [] } }]), json);
+
+    let request = json!({ "query": "{ countries { id } }" });
+    for (body, size) in [(json!([]), 0), (json!([request, request, request]), 3)] {
+        let (json, status) = send_request(&client, None, body);
+        assert_eq!(Status::BadRequest, status);
+        assert_json_eq!(
+            json!({
+                "errors": [{
+                    "message": format!(
+                        "Batches must contain between 1 and 2 operations, but this one contains {}",
+                        size
+                    ),
+                    "extensions": { "code": "BATCH_SIZE_INVALID" },
+                }],
+            }),
+            json,
+        );
+    }
+}
+
+#[test]
+fn test_tracing_spans() {
+    capture_spans();
+    let client = setup();
+
+    {
+        let con = get_db_con(&client);
+        UserFactory::default().insert(&con);
+    }
+
+    let body = json!({
+        "query": "query TracingTest { countries { name users { name } } }",
+        "operationName": "TracingTest",
+    });
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, Some("admin")));
+    let (_, status) = send_request(&client, Some(authorization), body);
+    assert_eq!(Status::Ok, status);
+
+    let spans = CLOSED_SPANS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|span| span.operation.as_deref() == Some("TracingTest"))
+        .cloned()
+        .collect::<Vec<_>>();
+    let names = spans.iter().map(|span| span.name).collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "db.query",
+            "db.query",
+            "Query.countries",
+            "Country.name",
+            "Country.users",
+            "User.name",
+            "graphql.operation",
+        ],
+        names,
+    );
+    // Resolvers record where their field is in the query, since spans of parent fields have
+    // closed by the time child fields are resolved.
+    let locations = spans
+        .iter()
+        .filter_map(|span| span.fields.get("graphql.location"))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["1:21", "1:33", "1:38", "1:46"], locations);
+
+    let queries = spans
+        .iter()
+        .filter(|span| span.name == "db.query")
+        .map(|span| {
+            (
+                span.fields["db.statement"].as_str(),
+                span.fields["db.rows"].as_str(),
+            )
         })
-        .to_string(),
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            (
+                r#"SELECT "countries"."id", "countries"."name" FROM "countries""#,
+                "1"
+            ),
+            (
+                r#"SELECT "users"."id", "users"."name", "users"."country_id" FROM "users" WHERE "users"."country_id" IN ($1)"#,
+                "1",
+            ),
+        ],
+        queries,
+    );
+    assert_eq!("0", spans.last().unwrap().fields["graphql.errors"]);
+}
+
+#[test]
+fn test_metrics() {
+    let client = setup();
+    let country_queries = metric(&client, r#"db_queries_total{table="countries"}"#);
+    let batch_errors = metric(
+        &client,
+        r#"graphql_errors_total{code="BATCH_SIZE_INVALID"}"#,
+    );
+    let other_requests = metric(&client, r#"graphql_requests_total{operation="other"}"#);
+
+    let body = json!({
+        "query": "query MetricsTest { countries { id } }",
+        "operationName": "MetricsTest",
+    });
+    let (_, status) = send_request(&client, None, body);
+    assert_eq!(Status::Ok, status);
+    let (_, status) = send_request(&client, None, json!([]));
+    assert_eq!(Status::BadRequest, status);
+    // Rejected operations aren't labelled with the names clients send.
+    for query in &["query RejectedTest { nope }", "query RejectedTest {"] {
+        let body = json!({ "query": query, "operationName": "RejectedTest" });
+        let (_, status) = send_request(&client, None, body);
+        assert_eq!(Status::BadRequest, status);
+    }
+
+    // Operations sent over WebSockets are counted too.
+    let session = Session::from_rocket(client.rocket()).expect("subscription session");
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let (sender, incoming) = mpsc::unbounded();
+        let (outgoing, mut messages) = mpsc::unbounded();
+        rocket::tokio::spawn(session.serve(incoming, outgoing));
+
+        send_ws_message(&sender, json!({ "type": "connection_init" }));
+        next_ws_message(&mut messages).await;
+        for (id, query, replies) in [
+            ("1", "query WebSocketMetricsTest { countries { id } }", 2),
+            ("2", "query RejectedTest {", 1),
+        ] {
+            let payload = json!({ "query": query, "operationName": query.split(' ').nth(1) });
+            send_ws_message(
+                &sender,
+                json!({ "type": "subscribe", "id": id, "payload": payload }),
+            );
+            for _ in 0..replies {
+                next_ws_message(&mut messages).await;
+            }
+        }
+    });
+
+    let response = client.get("/metrics").dispatch();
+    assert_eq!(Status::Ok, response.status());
+    assert_eq!(
+        Some("text/plain; version=0.0.4".to_string()),
+        response
+            .content_type()
+            .map(|content_type| content_type.to_string()),
+    );
+
+    assert_eq!(
+        1.0,
+        metric(
+            &client,
+            r#"graphql_requests_total{operation="MetricsTest"}"#
+        )
+    );
+    assert_eq!(
+        1.0,
+        metric(
+            &client,
+            r#"graphql_request_duration_seconds_count{operation="MetricsTest"}"#
+        )
+    );
+    assert_eq!(
+        0.0,
+        metric(
+            &client,
+            r#"graphql_requests_total{operation="RejectedTest"}"#
+        )
+    );
+    assert_eq!(
+        1.0,
+        metric(
+            &client,
+            r#"graphql_requests_total{operation="WebSocketMetricsTest"}"#
+        )
+    );
+    assert!(
+        metric(&client, r#"graphql_requests_total{operation="other"}"#) >= other_requests + 3.0
+    );
+    assert!(metric(&client, r#"db_queries_total{table="countries"}"#) > country_queries);
+    assert!(
+        metric(
+            &client,
+            r#"graphql_errors_total{code="BATCH_SIZE_INVALID"}"#
+        ) > batch_errors
+    );
+    assert_eq!(1.0, metric(&client, "db_pool_max_size"));
+
+    // Clients choose the names of operations, so only so many become labels.
+    let labels = OperationLabels::new(1);
+    assert_eq!("First", labels.label(Some("First")));
+    assert_eq!("other", labels.label(Some("Second")));
+    assert_eq!("First", labels.label(Some("First")));
+    assert_eq!("anonymous", labels.label(None));
+}
+
+#[test]
+fn test_counting_queries_by_table() {
+    capture_spans();
+    let client = setup();
+
+    {
+        let con = get_db_con(&client);
+        let country = CountryFactory::default().insert(&con);
+        UserFactory::default().country(&country).insert(&con);
+    }
+
+    // Ordering by country name selects from `countries` in a subquery, before `FROM "users"`.
+    let body = json!({
+        "query": r#"
+            query QueriesByTable {
+                userConnections(orderBy: [{ field: COUNTRY_NAME }]) { totalCount }
+                countries {
+                    userConnections(orderBy: [{ field: COUNTRY_NAME }]) { totalCount }
+                }
+            }
+        "#,
+        "operationName": "QueriesByTable",
+    });
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, Some("admin")));
+    let (_, status) = send_request(&client, Some(authorization), body);
+    assert_eq!(Status::Ok, status);
+
+    let mut tables = CLOSED_SPANS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|span| span.operation.as_deref() == Some("QueriesByTable"))
+        .filter(|span| span.name == "db.query")
+        .map(|span| crate::metrics::table_of(&span.fields["db.statement"]).map(str::to_string))
+        .collect::<Vec<_>>();
+    tables.sort();
+    assert_eq!(
+        vec![
+            Some("countries".to_string()),
+            Some("users".to_string()),
+            Some("users".to_string()),
+        ],
+        tables,
     );
+}
+
+#[derive(Clone, Factory)]
+#[factory(
+    model = User,
+    table = crate::schema::users,
+    connection = crate::db::TracedConnection
+)]
+struct UserFactory<'a> {
+    pub name: String,
+    pub country: Association<'a, Country, CountryFactory>,
+}
+
+impl Default for UserFactory<'_> {
+    fn default() -> Self {
+        Self {
+            name: "Bob".to_string(),
+            country: Association::default(),
+        }
+    }
+}
+
+#[derive(Clone, Factory)]
+#[factory(
+    model = Country,
+    table = crate::schema::countries,
+    connection = crate::db::TracedConnection
+)]
+struct CountryFactory {
+    pub name: String,
+}
+
+impl Default for CountryFactory {
+    fn default() -> Self {
+        Self {
+            name: "Copenhagen".to_string(),
+        }
+    }
+}
+
+/// The secret tokens in tests are signed with.
+const TEST_SECRET: &str = "secret";
+
+fn setup() -> Client {
+    setup_with_figment(rocket::Config::figment().merge(("auth.hs256_secret", TEST_SECRET)))
+}
+
+fn setup_with_figment(figment: Figment) -> Client {
+    let client = Client::tracked(crate::rocket_from_figment(figment)).expect("create test client");
+    let con = get_db_con(&client);
+    con.begin_test_transaction()
+        .expect("begin test transaction");
+    drop(con);
+
+    client
+}
+
+fn get_db_con(client: &Client) -> DbCon {
+    client
+        .rocket()
+        .state::<DbPool>()
+        .expect("db pool")
+        .get()
+        .expect("get db con")
+}
+
+/// The number of times `table` has been scanned in the current transaction. Tests run in a single
+/// transaction so this tells us how many queries a request ran against `table`.
+fn table_scans(client: &Client, table: &str) -> i64 {
+    let con = get_db_con(client);
+    diesel::select(
+        diesel::dsl::sql::<diesel::sql_types::BigInt>(
+            "(SELECT seq_scan + COALESCE(idx_scan, 0) FROM pg_stat_xact_user_tables \
+         WHERE relname = ",
+        )
+        .bind::<diesel::sql_types::Text, _>(table)
+        .sql(")"),
+    )
+    .get_result(&*con)
+    .expect("load table scans")
+}
+
+/// The value of the series `name`, including its labels, at `/metrics`. Metrics are shared by
+/// every test, so counters that other tests also increment can only be compared before and after.
+fn metric(client: &Client, name: &str) -> f64 {
+    let metrics = client.get("/metrics").dispatch().into_string().unwrap();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .map_or(0.0, |value| value.parse().expect("parse metric"))
+}
+
+/// Make a request as an admin.
+fn make_request(client: &Client, query: &str, variables: Option<Value>) -> (Value, Status) {
+    let authorization = format!("Bearer {}", token(TEST_SECRET, 60, Some("admin")));
+    make_request_as(client, Some(authorization), query, variables)
+}
+
+/// Make a request with the given `Authorization` header, or anonymously.
+fn make_request_as(
+    client: &Client,
+    authorization: Option<String>,
+    query: &str,
+    variables: Option<Value>,
+) -> (Value, Status) {
+    let body = json!({
+        "query": query,
+        "variables": variables.unwrap_or_else(|| json!({})),
+    });
+    send_request(client, authorization, body)
+}
+
+/// `POST` a request with any JSON body.
+fn send_request(client: &Client, authorization: Option<String>, body: Value) -> (Value, Status) {
+    let mut req = client
+        .post("/graphql")
+        .header(ContentType::JSON)
+        .body(body.to_string());
+    if let Some(authorization) = authorization {
+        req = req.header(Header::new("Authorization", authorization));
+    }
+
+    let response = req.dispatch();
+    let status = response.status();
+    let json = serde_json::from_str::<Value>(&response.into_string().unwrap()).unwrap();
+    (json, status)
+}
+
+fn send_ws_message(sender: &mpsc::UnboundedSender<Result<Message, Infallible>>, message: Value) {
+    sender
+        .unbounded_send(Ok(Message::Text(message.to_string())))
+        .expect("send websocket message");
+}
+
+async fn next_ws_message(messages: &mut mpsc::UnboundedReceiver<Message>) -> Value {
+    let message = rocket::tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .expect("timed out waiting for websocket message")
+        .expect("websocket closed");
+    serde_json::from_str(message.to_text().unwrap()).unwrap()
+}
+
+/// An admin, for sessions that aren't authenticated by a request.
+fn admin() -> CurrentUser {
+    CurrentUser {
+        subject: "1".to_string(),
+        role: Role::Admin,
+    }
+}
+
+/// A token for the subject "1" signed with `secret`, expiring in `expires_in` seconds.
+fn token(secret: &str, expires_in: i64, role: Option<&str>) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &json!({ "sub": "1", "exp": now + expires_in, "role": role }),
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap()
+}
+
+fn sha256_hash(query: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(query.as_bytes()))
+}
+
+fn user_id(user: &User) -> ID {
+    global_id::encode(NodeType::User, user.id)
+}
+
+fn country_id(country: &Country) -> ID {
+    global_id::encode(NodeType::Country, country.id)
+}
+
+/// Spans closed in any test, since the subscriber capturing them has to be global. Resolvers run
+/// on other threads than the test.
+static CLOSED_SPANS: Mutex<Vec<ClosedSpan>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone)]
+struct ClosedSpan {
+    name: &'static str,
+    /// The `graphql.operation.name` of the span or its closest ancestor with one.
+    operation: Option<String>,
+    fields: HashMap<String, String>,
+}
+
+struct CaptureSpans;
+
+struct SpanFields(HashMap<String, String>);
+
+fn capture_spans() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(CaptureSpans))
+            .expect("set global subscriber");
+    });
+}
+
+impl<S> Layer<S> for CaptureSpans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let mut fields = SpanFields(HashMap::new());
+        attrs.record(&mut fields);
+        ctx.span(id).unwrap().extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: layer::Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: layer::Context<'_, S>) {
+        let span = ctx.span(&id).unwrap();
+        let fields = span.extensions().get::<SpanFields>().unwrap().0.clone();
+        let operation = span.scope().find_map(|span| {
+            let extensions = span.extensions();
+            extensions
+                .get::<SpanFields>()?
+                .0
+                .get("graphql.operation.name")
+                .cloned()
+        });
+        CLOSED_SPANS.lock().unwrap().push(ClosedSpan {
+            name: span.name(),
+            operation,
+            fields,
+        });
+    }
+}
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
 
-    let mut response = req.dispatch();
-    let json = serde_json::from_str::<Value>(&response.body_string().unwrap()).unwrap();
-    (json, response.status())
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
 }